@@ -1,7 +1,9 @@
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use structopt::StructOpt;
 
+use crate::github::Repo;
+
 #[derive(Deserialize, Debug)]
 struct User {
     github_id: i64,
@@ -95,6 +97,199 @@ mod test_parse_github_id {
     }
 }
 
+#[derive(Debug, PartialEq)]
+enum ParseHerokuAppRepoError<'a> {
+    MissingEquals(&'a str),
+    RepoParseErr(&'a str),
+}
+
+impl<'a> std::fmt::Display for ParseHerokuAppRepoError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEquals(s) => write!(f, "invalid KEY=value: no `=` found in `{}`", s),
+            Self::RepoParseErr(s) => {
+                write!(
+                    f,
+                    "could not parse GitHub repository `org/name` from `{}`",
+                    s
+                )
+            }
+        }
+    }
+}
+
+/// Parse mapping of Heroku app names to GitHub repositories
+fn parse_heroku_app_repo_many(
+    s: &str,
+) -> Result<HashMap<HerokuAppName, Repo>, ParseHerokuAppRepoError> {
+    let mut apps = HashMap::new();
+    for mapping in s.split_whitespace() {
+        let pos = mapping
+            .find('=')
+            .ok_or_else(|| ParseHerokuAppRepoError::MissingEquals(mapping))?;
+        let repo = Repo::parse(&mapping[pos + 1..])
+            .ok_or_else(|| ParseHerokuAppRepoError::RepoParseErr(&mapping[pos + 1..]))?;
+        apps.insert(mapping[..pos].to_string(), repo);
+    }
+    Ok(apps)
+}
+
+#[cfg(test)]
+mod test_parse_heroku_app_repo {
+    use super::*;
+
+    #[test]
+    fn test_successful_many() {
+        let mut expected = HashMap::new();
+        expected.insert(
+            "acme-prod".to_string(),
+            Repo::parse("acme-corp/blog").unwrap(),
+        );
+        expected.insert(
+            "acme-api".to_string(),
+            Repo::parse("acme-corp/api").unwrap(),
+        );
+        let actual = parse_heroku_app_repo_many("acme-prod=acme-corp/blog acme-api=acme-corp/api")
+            .expect("should successfully parse");
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn test_invalid_repo() {
+        let actual = parse_heroku_app_repo_many("acme-prod=blog");
+        assert_eq!(
+            format!("{}", actual.err().expect("should have error")),
+            "could not parse GitHub repository `org/name` from `blog`".to_string()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseHerokuAppLabelError<'a> {
+    MissingEquals(&'a str),
+    EmptyLabel(&'a str),
+}
+
+impl<'a> std::fmt::Display for ParseHerokuAppLabelError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEquals(s) => write!(f, "invalid KEY=value: no `=` found in `{}`", s),
+            Self::EmptyLabel(s) => write!(f, "missing label for Heroku app `{}`", s),
+        }
+    }
+}
+
+/// Parse mapping of Heroku app names to the labels of deployed pull requests
+fn parse_heroku_app_label_many(
+    s: &str,
+) -> Result<HashMap<HerokuAppName, String>, ParseHerokuAppLabelError> {
+    let mut apps = HashMap::new();
+    for mapping in s.split_whitespace() {
+        let pos = mapping
+            .find('=')
+            .ok_or_else(|| ParseHerokuAppLabelError::MissingEquals(mapping))?;
+        let label = &mapping[pos + 1..];
+        if label.is_empty() {
+            return Err(ParseHerokuAppLabelError::EmptyLabel(&mapping[..pos]));
+        }
+        apps.insert(mapping[..pos].to_string(), label.to_string());
+    }
+    Ok(apps)
+}
+
+#[cfg(test)]
+mod test_parse_heroku_app_label {
+    use super::*;
+
+    #[test]
+    fn test_successful_many() {
+        let mut expected = HashMap::new();
+        expected.insert("acme-staging".to_string(), "deployed:staging".to_string());
+        expected.insert("acme-prod".to_string(), "deployed:production".to_string());
+        let actual = parse_heroku_app_label_many(
+            "acme-staging=deployed:staging acme-prod=deployed:production",
+        )
+        .expect("should successfully parse");
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn test_empty_label() {
+        let actual = parse_heroku_app_label_many("acme-prod=");
+        assert_eq!(
+            format!("{}", actual.err().expect("should have error")),
+            "missing label for Heroku app `acme-prod`".to_string()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseEmailSlackIdError<'a> {
+    MissingEquals(&'a str),
+    EmailParseErr(&'a str),
+}
+
+impl<'a> std::fmt::Display for ParseEmailSlackIdError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEquals(s) => write!(f, "invalid KEY=value: no `=` found in `{}`", s),
+            Self::EmailParseErr(s) => write!(f, "could not parse email from `{}`", s),
+        }
+    }
+}
+
+/// Parse mapping of git author emails to slack ids. Emails are lowercased so
+/// they match regardless of case.
+fn parse_email_slack_id_many(
+    s: &str,
+) -> Result<HashMap<String, SlackUserId>, ParseEmailSlackIdError> {
+    let mut users = HashMap::new();
+    for mapping in s.split_whitespace() {
+        let pos = mapping
+            .rfind('=')
+            .ok_or_else(|| ParseEmailSlackIdError::MissingEquals(mapping))?;
+        let email = &mapping[..pos];
+        if !email.contains('@') {
+            return Err(ParseEmailSlackIdError::EmailParseErr(email));
+        }
+        users.insert(email.to_lowercase(), mapping[pos + 1..].to_string());
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod test_parse_email_slack_id {
+    use super::*;
+
+    #[test]
+    fn test_successful_many() {
+        let mut expected = HashMap::new();
+        expected.insert("ghost@example.com".to_string(), "UAXQFKA3C".to_string());
+        expected.insert("chris@dignam.xyz".to_string(), "UAYMB3CNS".to_string());
+        let actual =
+            parse_email_slack_id_many("Ghost@Example.com=UAXQFKA3C chris@dignam.xyz=UAYMB3CNS")
+                .expect("should successfully parse");
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn test_invalid_email() {
+        let actual = parse_email_slack_id_many("ghost=UAXQFKA3C");
+        assert_eq!(
+            format!("{}", actual.err().expect("should have error")),
+            "could not parse email from `ghost`".to_string()
+        )
+    }
+}
+
+/// Parse a GitHub repository from `org/name`.
+fn parse_repo(s: &str) -> Result<Repo, String> {
+    Repo::parse(s)
+        .ok_or_else(|| format!("could not parse GitHub repository `org/name` from `{}`", s))
+}
+
+/// Parse whitespace separated secrets.
+fn parse_secrets(s: &str) -> HashSet<String> {
+    s.split_whitespace().map(String::from).collect()
+}
+
 /// Parse boolean from string.
 ///
 /// modified from https://github.com/TeXitoi/structopt/blob/b1174e5c9c0001386d7c0ca5e106f606d955eed1/examples/true_or_false.rs#L5-L11
@@ -108,14 +303,23 @@ fn true_or_false(s: &str) -> Result<bool, &'static str> {
 
 pub type GitHubUserId = i64;
 pub type SlackUserId = String;
+pub type HerokuAppName = String;
 
 /// A basic example
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "basic")]
 pub struct Opt {
-    /// a secret token for authenticating requests.
-    #[structopt(env = "SECRET")]
-    pub secret: String,
+    /// secrets for verifying the signature of Heroku webhook requests.
+    ///
+    /// separate multiple secrets with whitespace to rotate secrets without downtime.
+    #[structopt(long, env = "HEROKU_WEBHOOK_SECRETS", parse(from_str = parse_secrets), default_value = "")]
+    pub heroku_webhook_secrets: HashSet<String>,
+
+    /// legacy secret token for authenticating requests via the `auth_token` query parameter.
+    ///
+    /// query parameter authentication is disabled unless this is set.
+    #[structopt(long, env = "SECRET")]
+    pub secret: Option<String>,
 
     /// Github App ID for authenticating with GitHub API
     #[structopt(env = "GITHUB_APP_ID")]
@@ -125,10 +329,6 @@ pub struct Opt {
     #[structopt(env = "GITHUB_APP_PRIVATE_KEY")]
     pub github_app_private_key: String,
 
-    /// Github App installation ID
-    #[structopt(env = "GITHUB_APP_INSTALL_ID")]
-    pub github_app_install_id: String,
-
     /// Heroku API token
     #[structopt(env = "EVE_HEROKU_TOKEN")]
     pub heroku_token: String,
@@ -143,6 +343,47 @@ pub struct Opt {
     #[structopt(env="GITHUB_SLACK_USER_IDS", parse(try_from_str = parse_github_id_slack_id_many), number_of_values = 1)]
     pub github_slack_user_ids: HashMap<GitHubUserId, SlackUserId>,
 
+    /// git author email to slack id mappings
+    ///
+    /// used for commits by emails that aren't linked to a GitHub account.
+    /// ex: ghost@example.com=UAXQFKA3C
+    #[structopt(long, env = "EMAIL_SLACK_USER_IDS", parse(try_from_str = parse_email_slack_id_many), default_value = "")]
+    pub email_slack_user_ids: HashMap<String, SlackUserId>,
+
+    /// list merged pull requests instead of their commits, notifying the pull request authors.
+    #[structopt(long, env = "GROUP_BY_PULL_REQUEST", parse(try_from_str = true_or_false), default_value = "false")]
+    pub group_by_pull_request: bool,
+
+    /// also notify whoever merged a pull request. requires `GROUP_BY_PULL_REQUEST`.
+    #[structopt(long, env = "NOTIFY_PULL_REQUEST_MERGERS", parse(try_from_str = true_or_false), default_value = "false")]
+    pub notify_pull_request_mergers: bool,
+
+    /// also notify the approving reviewers of a pull request. requires `GROUP_BY_PULL_REQUEST`.
+    #[structopt(long, env = "NOTIFY_PULL_REQUEST_REVIEWERS", parse(try_from_str = true_or_false), default_value = "false")]
+    pub notify_pull_request_reviewers: bool,
+
+    /// keep a comment on each merged pull request listing the heroku apps it was deployed to.
+    #[structopt(long, env = "COMMENT_ON_PULL_REQUESTS", parse(try_from_str = true_or_false), default_value = "false")]
+    pub comment_on_pull_requests: bool,
+
+    /// record each release as a GitHub deployment to an environment named after the heroku app.
+    #[structopt(long, env = "GITHUB_DEPLOYMENTS", parse(try_from_str = true_or_false), default_value = "false")]
+    pub github_deployments: bool,
+
+    /// heroku app name to github repository mappings
+    ///
+    /// used when a webhook doesn't specify `github_org_name` and `github_repo_name`.
+    /// ex: acme-prod=acme-corp/blog acme-staging=acme-corp/blog
+    #[structopt(long, env = "HEROKU_APP_GITHUB_REPOS", parse(try_from_str = parse_heroku_app_repo_many), default_value = "")]
+    pub heroku_app_github_repos: HashMap<HerokuAppName, Repo>,
+
+    /// heroku app name to the label added to merged pull requests deployed to the app
+    ///
+    /// labels are removed when the pull requests are rolled back.
+    /// ex: acme-staging=deployed:staging acme-prod=deployed:production
+    #[structopt(long, env = "HEROKU_APP_DEPLOY_LABELS", parse(try_from_str = parse_heroku_app_label_many), default_value = "")]
+    pub heroku_app_deploy_labels: HashMap<HerokuAppName, String>,
+
     /// enable debug mode for http server.
     #[structopt(env="DEBUG", parse(try_from_str = true_or_false), default_value="false")]
     pub debug: bool,
@@ -150,8 +391,113 @@ pub struct Opt {
     /// configure port for http server.
     #[structopt(env = "PORT", default_value = "8000")]
     pub port: u16,
+
+    /// path to the SQLite database used to persist queued jobs.
+    #[structopt(long, env = "DATABASE_PATH", default_value = "eve.sqlite3")]
+    pub database_path: String,
+
+    /// number of worker threads processing queued jobs.
+    #[structopt(long, env = "WORKER_COUNT", default_value = "4")]
+    pub worker_count: usize,
+
+    /// number of times a job is attempted before it is dead-lettered.
+    #[structopt(long, env = "MAX_JOB_ATTEMPTS", default_value = "8")]
+    pub max_job_attempts: i64,
+
+    /// seconds spent retrying a rate limited or failed API request before the job fails.
+    #[structopt(long, env = "MAX_RETRY_SECONDS", default_value = "60")]
+    pub max_retry_seconds: u64,
+
+    /// minutes after a release during which dyno crashes are reported to the
+    /// authors of the release.
+    #[structopt(long, env = "DYNO_CRASH_WINDOW_MINUTES", default_value = "15")]
+    pub dyno_crash_window_minutes: i64,
+
+    /// base URL of the Heroku Platform API.
+    #[structopt(long, env = "HEROKU_API_URL", default_value = "https://api.heroku.com")]
+    pub heroku_api_url: String,
+
+    /// base URL of the GitHub API.
+    ///
+    /// for GitHub Enterprise Server, use `https://<hostname>/api/v3`.
+    #[structopt(long, env = "GITHUB_API_URL", default_value = "https://api.github.com")]
+    pub github_api_url: String,
+
+    /// base URL of the GitHub web interface, like `https://<hostname>` for GitHub Enterprise Server.
+    #[structopt(long, env = "GITHUB_WEB_URL", default_value = "https://github.com")]
+    pub github_web_url: String,
+
+    /// base URL of the Slack Web API.
+    #[structopt(long, env = "SLACK_API_URL", default_value = "https://slack.com/api")]
+    pub slack_api_url: String,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "eve")]
+pub enum Command {
+    /// manage the Heroku webhook subscriptions that deliver events to Eve.
+    Heroku {
+        /// public URL of this Eve server, like https://eve.example.com.
+        #[structopt(long, env = "EVE_URL")]
+        url: String,
+
+        /// Heroku API token
+        #[structopt(long, env = "EVE_HEROKU_TOKEN", hide_env_values = true)]
+        heroku_token: String,
+
+        /// base URL of the Heroku Platform API.
+        #[structopt(long, env = "HEROKU_API_URL", default_value = "https://api.heroku.com")]
+        heroku_api_url: String,
+
+        #[structopt(subcommand)]
+        cmd: HerokuCommand,
+    },
 }
 
-pub fn parse_args() -> Opt {
-    Opt::from_args()
+#[derive(StructOpt, Debug, Clone)]
+pub enum HerokuCommand {
+    /// create or update Eve's webhook subscription for an app.
+    Subscribe {
+        /// Heroku app to subscribe to.
+        #[structopt(long)]
+        app: HerokuAppName,
+
+        /// GitHub repository of the app, like acme-corp/blog.
+        ///
+        /// the repository is inferred from the app when omitted.
+        #[structopt(long, parse(try_from_str = parse_repo))]
+        repo: Option<Repo>,
+
+        /// secret for signing webhook requests.
+        ///
+        /// when omitted, an existing subscription keeps its secret and a new
+        /// subscription gets a generated secret.
+        #[structopt(long)]
+        secret: Option<String>,
+    },
+    /// list Eve's webhook subscriptions for an app.
+    List {
+        #[structopt(long)]
+        app: HerokuAppName,
+    },
+    /// remove Eve's webhook subscriptions for an app.
+    Remove {
+        #[structopt(long)]
+        app: HerokuAppName,
+    },
+}
+
+pub enum Args {
+    Server(Opt),
+    Command(Command),
+}
+
+/// Parse a subcommand, or the server's options when no subcommand is given.
+/// Subcommands are parsed on their own so they don't require the server's
+/// configuration.
+pub fn parse_args() -> Args {
+    match std::env::args().nth(1).as_deref() {
+        Some("heroku") => Args::Command(Command::from_args()),
+        _ => Args::Server(Opt::from_args()),
+    }
 }
@@ -0,0 +1,441 @@
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, StatusCode};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cli::Opt;
+use crate::github::GitHubClient;
+
+const USER_AGENT: &str = "chdsbd/eve";
+
+/// Delay before the first retry of a request. Later retries double it.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(32);
+/// GitHub asks for at least a minute between retries of requests rejected by
+/// a secondary rate limit without a `Retry-After` header.
+const SECONDARY_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+fn backoff(attempt: u32) -> Duration {
+    (INITIAL_BACKOFF * 2u32.pow(attempt.min(5))).min(MAX_BACKOFF)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(Duration::from_secs(
+        (retry_at.timestamp() - now.timestamp()).max(0) as u64,
+    ))
+}
+
+/// Time until GitHub's primary rate limit resets, if it has been used up.
+fn rate_limit_reset(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    if header_str(headers, "x-ratelimit-remaining")? != "0" {
+        return None;
+    }
+    let reset = header_str(headers, "x-ratelimit-reset")?
+        .parse::<i64>()
+        .ok()?;
+    Some(Duration::from_secs(
+        (reset - now.timestamp()).max(0) as u64 + 1,
+    ))
+}
+
+/// Secondary rate limits can only be told apart from other 403s by the
+/// message, which older responses call "abuse detection".
+fn is_secondary_rate_limit(body: &[u8]) -> bool {
+    let body = String::from_utf8_lossy(body).to_lowercase();
+    body.contains("secondary rate limit") || body.contains("abuse detection")
+}
+
+/// How long to wait before retrying a response, or `None` if the response
+/// shouldn't be retried. `body` is only read for 403s.
+fn retry_delay(
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+    attempt: u32,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let retry_after =
+        header_str(headers, RETRY_AFTER.as_str()).and_then(|value| parse_retry_after(value, now));
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => Some(retry_after.unwrap_or_else(|| backoff(attempt))),
+        // GitHub rejects rate limited requests with a 403. Secondary rate
+        // limits usually set `Retry-After` and the primary rate limit resets
+        // at `X-RateLimit-Reset`.
+        StatusCode::FORBIDDEN => retry_after
+            .or_else(|| rate_limit_reset(headers, now))
+            .or_else(|| {
+                if is_secondary_rate_limit(body) {
+                    Some(SECONDARY_RATE_LIMIT_WAIT)
+                } else {
+                    None
+                }
+            }),
+        // a request that isn't idempotent, like creating a comment, may have
+        // been applied before the server failed.
+        status if status.is_server_error() && method.is_idempotent() => {
+            Some(retry_after.unwrap_or_else(|| backoff(attempt)))
+        }
+        _ => None,
+    }
+}
+
+/// HTTP client for an upstream API, with paths relative to its base URL.
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+    http: Client,
+    base_url: String,
+    /// Requests aren't retried past this long after they were first sent.
+    max_retry_time: Duration,
+}
+
+impl ApiClient {
+    pub fn new(http: Client, base_url: &str, max_retry_time: Duration) -> Self {
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            max_retry_time,
+        }
+    }
+
+    /// Read the body of a response, returning it with an equivalent response.
+    fn buffer(res: Response) -> reqwest::Result<(Response, Vec<u8>)> {
+        let mut builder = http::Response::builder()
+            .status(res.status())
+            .version(res.version());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = res.headers().clone();
+        }
+        let body = res.bytes()?.to_vec();
+        let res = builder
+            .body(body.clone())
+            .expect("response parts are already valid");
+        Ok((res.into(), body))
+    }
+
+    /// Send a request, retrying rate limited requests and failed connections
+    /// with backoff until `max_retry_time` has passed. Server errors and
+    /// timeouts are only retried for idempotent methods. The last response is
+    /// returned if retries run out.
+    pub fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let deadline = Instant::now() + self.max_retry_time;
+        let mut request = request.build()?;
+        let mut attempt = 0;
+        loop {
+            let method = request.method().clone();
+            // requests with streaming bodies can't be retried.
+            let retry = request.try_clone();
+            let (res, body) = match self.http.execute(request) {
+                Ok(res) if res.status() == StatusCode::FORBIDDEN => match Self::buffer(res) {
+                    Ok((res, body)) => (Ok(res), body),
+                    Err(e) => (Err(e), Vec::new()),
+                },
+                res => (res, Vec::new()),
+            };
+            let delay = match &res {
+                Ok(res) => retry_delay(
+                    &method,
+                    res.status(),
+                    res.headers(),
+                    &body,
+                    attempt,
+                    Utc::now(),
+                ),
+                // the request wasn't sent if the connection failed.
+                Err(e) if e.is_connect() => Some(backoff(attempt)),
+                Err(e) if e.is_timeout() && method.is_idempotent() => Some(backoff(attempt)),
+                Err(_) => None,
+            };
+            match (retry, delay) {
+                (Some(retry), Some(delay)) if Instant::now() + delay <= deadline => {
+                    thread::sleep(delay);
+                    request = retry;
+                    attempt += 1;
+                }
+                _ => return res,
+            }
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, &format!("{}{}", self.base_url, path))
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.request(Method::POST, path)
+    }
+
+    pub fn patch(&self, path: &str) -> RequestBuilder {
+        self.request(Method::PATCH, path)
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.request(Method::DELETE, path)
+    }
+}
+
+/// Clients for the Heroku, GitHub and Slack APIs. They share a connection
+/// pool and token cache, so clones are cheap.
+#[derive(Clone, Debug)]
+pub struct Clients {
+    pub heroku: ApiClient,
+    pub github: GitHubClient,
+    pub slack: ApiClient,
+}
+
+/// Create the HTTP client shared by the API clients.
+pub fn http_client() -> Client {
+    Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("could not create HTTP client")
+}
+
+impl Clients {
+    pub fn new(config: &Opt) -> Self {
+        let http = http_client();
+        let max_retry_time = Duration::from_secs(config.max_retry_seconds);
+        Self {
+            heroku: ApiClient::new(http.clone(), &config.heroku_api_url, max_retry_time),
+            github: GitHubClient::new(ApiClient::new(
+                http.clone(),
+                &config.github_api_url,
+                max_retry_time,
+            )),
+            slack: ApiClient::new(http, &config.slack_api_url, max_retry_time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let now = Utc.ymd(2020, 6, 1).and_hms(14, 2, 0);
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::TOO_MANY_REQUESTS,
+                &headers(&[("retry-after", "30")]),
+                b"",
+                0,
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::TOO_MANY_REQUESTS,
+                &headers(&[("retry-after", "Mon, 01 Jun 2020 14:02:10 GMT")]),
+                b"",
+                0,
+                now
+            ),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::SERVICE_UNAVAILABLE,
+                &headers(&[]),
+                b"",
+                2,
+                now
+            ),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::BAD_GATEWAY,
+                &headers(&[]),
+                b"",
+                10,
+                now
+            ),
+            Some(MAX_BACKOFF)
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::FORBIDDEN,
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", "1591020140")
+                ]),
+                b"",
+                0,
+                now
+            ),
+            Some(Duration::from_secs(21))
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::FORBIDDEN,
+                &headers(&[("retry-after", "60")]),
+                b"",
+                0,
+                now
+            ),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::FORBIDDEN,
+                &headers(&[("x-ratelimit-remaining", "4999")]),
+                b"",
+                0,
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::FORBIDDEN,
+                &headers(&[("x-ratelimit-remaining", "4999")]),
+                br#"{"message": "You have exceeded a secondary rate limit. Please wait a few minutes before you try again."}"#,
+                0,
+                now
+            ),
+            Some(SECONDARY_RATE_LIMIT_WAIT)
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::POST,
+                StatusCode::FORBIDDEN,
+                &headers(&[]),
+                br#"{"message": "You have triggered an abuse detection mechanism. Please wait a few minutes before you try again."}"#,
+                0,
+                now
+            ),
+            Some(SECONDARY_RATE_LIMIT_WAIT)
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::GET,
+                StatusCode::NOT_FOUND,
+                &headers(&[]),
+                b"",
+                0,
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::POST,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &headers(&[]),
+                b"",
+                0,
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            retry_delay(
+                &Method::POST,
+                StatusCode::TOO_MANY_REQUESTS,
+                &headers(&[]),
+                b"",
+                0,
+                now
+            ),
+            Some(INITIAL_BACKOFF)
+        );
+    }
+
+    /// Serve `response` to every request, returning the server's URL and a
+    /// count of the requests it received.
+    fn serve(response: &'static [u8]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).unwrap();
+                received.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(response).unwrap();
+            }
+        });
+        (base_url, requests)
+    }
+
+    #[test]
+    fn test_post_not_resent_after_server_error() {
+        let (base_url, requests) = serve(
+            b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let client = ApiClient::new(http_client(), &base_url, Duration::from_secs(5));
+
+        let res = client
+            .send(
+                client
+                    .post("/repos/acme-corp/blog/issues/12/comments")
+                    .body("{}"),
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_forbidden_body_returned() {
+        let (base_url, requests) = serve(
+            b"HTTP/1.1 403 Forbidden\r\ncontent-type: application/json\r\ncontent-length: 31\r\nconnection: close\r\n\r\n{\"message\": \"Resource private\"}",
+        );
+        let client = ApiClient::new(http_client(), &base_url, Duration::from_secs(5));
+
+        let res = client
+            .send(client.get("/repos/acme-corp/blog/installation"))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            res.headers()["content-type"],
+            HeaderValue::from_static("application/json")
+        );
+        assert_eq!(res.text().unwrap(), r#"{"message": "Resource private"}"#);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
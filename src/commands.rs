@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use crate::cli::{Command, HerokuCommand};
+use crate::client::{self, ApiClient};
+use crate::github::Repo;
+use crate::heroku::{self, HerokuWebhook, HerokuWebhookOpts};
+use crate::EveError;
+
+/// How long a rate limited or failed Heroku request is retried.
+const MAX_RETRY_TIME: Duration = Duration::from_secs(30);
+
+/// Webhook entities Eve handles.
+const WEBHOOK_INCLUDE: &[&str] = &["api:release", "api:build", "api:dyno"];
+
+/// URL of the webhook endpoint of the Eve server at `base_url`.
+fn webhook_url(base_url: &str, repo: Option<&Repo>) -> String {
+    let url = format!("{}/heroku_webhook", base_url.trim_end_matches('/'));
+    match repo {
+        Some(repo) => format!(
+            "{}?github_org_name={}&github_repo_name={}",
+            url, repo.org, repo.name
+        ),
+        None => url,
+    }
+}
+
+/// Check whether `webhook` delivers to the Eve server at `base_url`.
+fn is_eve_webhook(base_url: &str, webhook: &HerokuWebhook) -> bool {
+    let url = webhook_url(base_url, None);
+    webhook.url == url || webhook.url.starts_with(&format!("{}?", url))
+}
+
+/// Generate a random secret for signing webhook requests.
+fn generate_secret() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn list_eve_webhooks(
+    client: &ApiClient,
+    app: &str,
+    base_url: &str,
+    token: &str,
+) -> Result<Vec<HerokuWebhook>, EveError> {
+    Ok(heroku::list_webhooks(client, app, token)?
+        .into_iter()
+        .filter(|webhook| is_eve_webhook(base_url, webhook))
+        .collect())
+}
+
+fn run_heroku_command(
+    cmd: &HerokuCommand,
+    base_url: &str,
+    client: &ApiClient,
+    token: &str,
+) -> Result<(), EveError> {
+    match cmd {
+        HerokuCommand::Subscribe { app, repo, secret } => {
+            let url = webhook_url(base_url, repo.as_ref());
+            let opts = HerokuWebhookOpts {
+                include: WEBHOOK_INCLUDE,
+                level: "notify",
+                url: &url,
+                secret: secret.as_deref(),
+            };
+            match list_eve_webhooks(client, app, base_url, token)?.first() {
+                // keep the secret of an existing webhook unless one is given,
+                // since the server only accepts its configured secrets.
+                Some(existing) => {
+                    let webhook = heroku::update_webhook(client, app, &existing.id, &opts, token)?;
+                    println!("updated {} for {} ({})", webhook.url, app, webhook.id);
+                }
+                None => {
+                    let generated_secret = match secret {
+                        Some(_) => None,
+                        None => Some(generate_secret()),
+                    };
+                    let opts = HerokuWebhookOpts {
+                        secret: opts.secret.or_else(|| generated_secret.as_deref()),
+                        ..opts
+                    };
+                    let webhook = heroku::create_webhook(client, app, &opts, token)?;
+                    println!("subscribed {} to {} ({})", app, webhook.url, webhook.id);
+                    if let Some(secret) = generated_secret {
+                        println!("add this secret to HEROKU_WEBHOOK_SECRETS: {}", secret);
+                    }
+                }
+            }
+        }
+        HerokuCommand::List { app } => {
+            for webhook in list_eve_webhooks(client, app, base_url, token)? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    webhook.id,
+                    webhook.level,
+                    webhook.include.join(","),
+                    webhook.url
+                );
+            }
+        }
+        HerokuCommand::Remove { app } => {
+            for webhook in list_eve_webhooks(client, app, base_url, token)? {
+                heroku::delete_webhook(client, app, &webhook.id, token)?;
+                println!("removed {} ({})", webhook.url, webhook.id);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn run(cmd: &Command) -> Result<(), EveError> {
+    match cmd {
+        Command::Heroku {
+            url,
+            heroku_token,
+            heroku_api_url,
+            cmd,
+        } => {
+            let client = ApiClient::new(client::http_client(), heroku_api_url, MAX_RETRY_TIME);
+            run_heroku_command(cmd, url, &client, heroku_token)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn webhook(url: &str) -> HerokuWebhook {
+        HerokuWebhook {
+            id: "01234567-89ab-cdef-0123-456789abcdef".to_string(),
+            url: url.to_string(),
+            include: vec!["api:release".to_string()],
+            level: "notify".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_webhook_url() {
+        assert_eq!(
+            webhook_url("https://eve.example.com/", None),
+            "https://eve.example.com/heroku_webhook"
+        );
+        assert_eq!(
+            webhook_url(
+                "https://eve.example.com",
+                Repo::parse("acme-corp/blog").as_ref()
+            ),
+            "https://eve.example.com/heroku_webhook?github_org_name=acme-corp&github_repo_name=blog"
+        );
+    }
+
+    #[test]
+    fn test_is_eve_webhook() {
+        let base_url = "https://eve.example.com";
+        assert!(is_eve_webhook(
+            base_url,
+            &webhook("https://eve.example.com/heroku_webhook")
+        ));
+        assert!(is_eve_webhook(
+            base_url,
+            &webhook("https://eve.example.com/heroku_webhook?github_org_name=acme-corp&github_repo_name=blog")
+        ));
+        assert!(!is_eve_webhook(
+            base_url,
+            &webhook("https://eve.example.com/heroku_webhook_v2")
+        ));
+        assert!(!is_eve_webhook(
+            base_url,
+            &webhook("https://ci.example.com/heroku_webhook")
+        ));
+    }
+}
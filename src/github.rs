@@ -1,11 +1,22 @@
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{RequestBuilder, Response};
 use reqwest::header::{ACCEPT, AUTHORIZATION};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::client::ApiClient;
+
+/// Version 3 of the REST API, which GitHub Enterprise Server also serves at
+/// `https://<hostname>/api/v3`.
+const ACCEPT_V3: &str = "application/vnd.github.v3+json";
+
 #[derive(Debug)]
 pub enum GitHubError {
     JsonWebTokenCreation(jsonwebtoken::errors::Error),
@@ -24,6 +35,28 @@ impl std::convert::From<jsonwebtoken::errors::Error> for GitHubError {
     }
 }
 
+/// A GitHub repository, like `acme-corp/blog`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repo {
+    pub org: String,
+    pub name: String,
+}
+
+impl Repo {
+    /// Parse a repository from `org/name`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let pos = s.find('/')?;
+        let (org, name) = (&s[..pos], &s[pos + 1..]);
+        if org.is_empty() || name.is_empty() || name.contains('/') {
+            return None;
+        }
+        Some(Self {
+            org: org.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claim {
     /// Issued at
@@ -57,37 +90,192 @@ fn generate_jwt(
     )
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct GithubAccessToken {
-    expires_at: String,
+    expires_at: DateTime<Utc>,
     permissions: Value,
     repository_selection: String,
     token: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct Installation {
+    id: i64,
+}
+
+/// Find the installation of the app on a repository.
+/// https://developer.github.com/v3/apps/#get-a-repository-installation-for-the-authenticated-app
+fn get_repo_installation(
+    client: &ApiClient,
+    jwt: &str,
+    org: &str,
+    repo: &str,
+) -> Result<Installation, GitHubError> {
+    let res = client.send(
+        client
+            .get(&format!(
+                "/repos/{org}/{repo}/installation",
+                org = org,
+                repo = repo
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", jwt))
+            .header(ACCEPT, ACCEPT_V3),
+    )?;
+    res.error_for_status_ref()?;
+
+    Ok(res.json::<Installation>()?)
+}
+
 struct CreateAccessTokenForInstall<'a> {
-    install_id: &'a str,
+    install_id: i64,
     jwt: &'a str,
 }
 
 /// https://developer.github.com/v3/apps/#create-an-installation-access-token-for-an-app
 fn create_access_token_for_install(
+    client: &ApiClient,
     params: CreateAccessTokenForInstall,
 ) -> Result<GithubAccessToken, GitHubError> {
-    let res = reqwest::blocking::Client::new()
-        .post(&format!(
-            "https://api.github.com/app/installations/{install_id}/access_tokens",
-            install_id = params.install_id
-        ))
-        .header("User-Agent", "chdsbd/heroku-deploy-notifier")
-        .header(AUTHORIZATION, format!("Bearer {}", params.jwt))
-        .header(ACCEPT, "application/vnd.github.machine-man-preview+json")
-        .send()?;
+    let res = client.send(
+        client
+            .post(&format!(
+                "/app/installations/{install_id}/access_tokens",
+                install_id = params.install_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", params.jwt))
+            .header(ACCEPT, ACCEPT_V3),
+    )?;
     res.error_for_status_ref()?;
 
     Ok(res.json::<GithubAccessToken>()?)
 }
 
+/// Cached tokens are replaced this long before they expire, so a token doesn't
+/// expire between being read from the cache and being used.
+const TOKEN_EXPIRY_MARGIN_SECONDS: i64 = 5 * 60;
+
+/// Installation IDs and access tokens shared between threads.
+///
+/// The app may be installed on several organizations, so the installation is
+/// looked up per repository. Tokens are valid for an hour, so reusing them
+/// saves creating a token for every request.
+#[derive(Clone, Default, Debug)]
+pub struct TokenCache {
+    /// Installation IDs keyed by `org/repo`.
+    installations: Arc<Mutex<HashMap<String, i64>>>,
+    /// Access tokens keyed by installation ID.
+    tokens: Arc<Mutex<HashMap<i64, GithubAccessToken>>>,
+}
+
+/// Credentials for making requests as the app's installation on a repository.
+#[derive(Clone, Copy)]
+pub struct RepoAuth<'a> {
+    pub private_key: &'a str,
+    pub app_id: &'a str,
+    pub org: &'a str,
+    pub repo: &'a str,
+}
+
+impl TokenCache {
+    /// Get a token for the installation on the repository, creating one if
+    /// there is no cached token or it expires soon.
+    fn get(
+        &self,
+        client: &ApiClient,
+        params: &RepoAuth,
+        now: DateTime<Utc>,
+    ) -> Result<String, GitHubError> {
+        let jwt = || generate_jwt(params.private_key, params.app_id);
+        let repo_key = format!("{}/{}", params.org, params.repo);
+        // the locks aren't held across requests, so a slow request to GitHub
+        // doesn't block other repositories. Threads that miss the cache at
+        // the same time may each create a token, and the last one is kept.
+        let cached_install_id = self
+            .installations
+            .lock()
+            .expect("installation cache lock poisoned")
+            .get(&repo_key)
+            .copied();
+        let install_id = match cached_install_id {
+            Some(install_id) => install_id,
+            None => {
+                let install_id =
+                    get_repo_installation(client, &jwt()?, params.org, params.repo)?.id;
+                self.installations
+                    .lock()
+                    .expect("installation cache lock poisoned")
+                    .insert(repo_key, install_id);
+                install_id
+            }
+        };
+        let cached_token = self
+            .tokens
+            .lock()
+            .expect("token cache lock poisoned")
+            .get(&install_id)
+            .filter(|token| {
+                token.expires_at - chrono::Duration::seconds(TOKEN_EXPIRY_MARGIN_SECONDS) > now
+            })
+            .map(|token| token.token.clone());
+        if let Some(token) = cached_token {
+            return Ok(token);
+        }
+        let token = create_access_token_for_install(
+            client,
+            CreateAccessTokenForInstall {
+                jwt: &jwt()?,
+                install_id,
+            },
+        )?;
+        let value = token.token.clone();
+        self.tokens
+            .lock()
+            .expect("token cache lock poisoned")
+            .insert(install_id, token);
+        Ok(value)
+    }
+
+    /// Forget the installation and token for a repository, like after GitHub
+    /// rejects the token because the app was reinstalled.
+    fn invalidate(&self, org: &str, repo: &str) {
+        let install_id = self
+            .installations
+            .lock()
+            .expect("installation cache lock poisoned")
+            .remove(&format!("{}/{}", org, repo));
+        if let Some(install_id) = install_id {
+            self.tokens
+                .lock()
+                .expect("token cache lock poisoned")
+                .remove(&install_id);
+        }
+    }
+}
+
+/// Client for the GitHub API that caches the app's installation tokens.
+#[derive(Clone, Debug)]
+pub struct GitHubClient {
+    api: ApiClient,
+    tokens: TokenCache,
+}
+
+impl GitHubClient {
+    pub fn new(api: ApiClient) -> Self {
+        Self {
+            api,
+            tokens: TokenCache::default(),
+        }
+    }
+}
+
+impl std::ops::Deref for GitHubClient {
+    type Target = ApiClient;
+
+    fn deref(&self) -> &ApiClient {
+        &self.api
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Actor {
     pub login: String,
@@ -97,6 +285,8 @@ pub struct Actor {
 }
 #[derive(Deserialize, Debug)]
 pub struct CommitAuthor {
+    pub name: String,
+    pub email: String,
     pub date: String,
 }
 #[derive(Deserialize, Debug)]
@@ -106,57 +296,573 @@ pub struct Commit {
     pub author: CommitAuthor,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct CommitParent {
+    pub sha: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CommitNode {
     pub sha: String,
     pub commit: Commit,
-    pub author: Actor,
+    /// Merge commits have more than one parent.
+    #[serde(default)]
+    pub parents: Vec<CommitParent>,
+    /// `None` when the commit's author email isn't linked to a GitHub account.
+    pub author: Option<Actor>,
     pub html_url: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct CommitComparison {
+    /// One of `ahead`, `behind`, `diverged` or `identical`.
+    pub status: String,
     pub url: String,
     pub html_url: String,
     pub permalink_url: String,
+    /// Number of commits in the comparison, which may be more than were
+    /// fetched into `commits`.
+    pub total_commits: i64,
     pub commits: Vec<CommitNode>,
 }
 
+/// Get the emails from the `Co-authored-by` trailers of a commit message.
+/// https://docs.github.com/en/github/committing-changes-to-your-project/creating-a-commit-with-multiple-authors
+pub fn co_author_emails(message: &str) -> Vec<&str> {
+    message
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (key, value) = (line.get(..15)?, &line[15..]);
+            if !key.eq_ignore_ascii_case("co-authored-by:") {
+                return None;
+            }
+            let start = value.find('<')? + 1;
+            let end = start + value[start..].find('>')?;
+            Some(value[start..end].trim())
+        })
+        .filter(|email| !email.is_empty())
+        .collect()
+}
+
+/// Get the GitHub user ID from a noreply email, like
+/// `1929960+chdsbd@users.noreply.github.com`. GitHub Enterprise Server uses
+/// `users.noreply.<hostname>`.
+pub fn noreply_email_user_id(email: &str) -> Option<i64> {
+    let email = email.to_lowercase();
+    let pos = email.rfind('@')?;
+    if !email[pos + 1..].starts_with("users.noreply.") {
+        return None;
+    }
+    let local = &email[..pos];
+    let pos = local.find('+')?;
+    local[..pos].parse().ok()
+}
+
+/// Commits fetched per page of a comparison.
+const COMPARE_PAGE_SIZE: i64 = 100;
+/// Pages of a comparison fetched before the remaining commits are left out.
+const MAX_COMPARE_PAGES: i64 = 10;
+
+/// Send a request as the installation on a repository. `request` is called
+/// with an access token, and called again with a new token if GitHub rejects
+/// the cached one.
+fn send_as_installation(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    request: impl Fn(&str) -> RequestBuilder,
+) -> Result<Response, GitHubError> {
+    send_as_installation_accepting(client, auth, ACCEPT_V3, request)
+}
+
+/// Like `send_as_installation`, accepting a media type like an API preview.
+fn send_as_installation_accepting(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    accept: &str,
+    request: impl Fn(&str) -> RequestBuilder,
+) -> Result<Response, GitHubError> {
+    let token = client.tokens.get(client, auth, Utc::now())?;
+    let res = client.send(request(&token).header(ACCEPT, accept))?;
+    if res.status() != StatusCode::UNAUTHORIZED {
+        return Ok(res);
+    }
+    // the cached token was revoked before it expired, so retry with a new one.
+    client.tokens.invalidate(auth.org, auth.repo);
+    let token = client.tokens.get(client, auth, Utc::now())?;
+    Ok(client.send(request(&token).header(ACCEPT, accept))?)
+}
+
 pub struct Compare<'a> {
-    pub private_key: &'a str,
-    pub app_id: &'a str,
-    pub install_id: &'a str,
-    pub org: &'a str,
-    pub repo: &'a str,
+    pub auth: RepoAuth<'a>,
     pub base: &'a str,
     pub head: &'a str,
 }
 
-pub fn compare(params: Compare) -> Result<CommitComparison, GitHubError> {
+pub fn compare(client: &GitHubClient, params: Compare) -> Result<CommitComparison, GitHubError> {
     // https://developer.github.com/v3/repos/commits/#compare-two-commits
     // https://api.github.com/repos/chdsbd/kodiak/compare/7c68a71a87d12cc2404aed192840674af84f3df4...master
-    let github_compare_url = format!(
-        "https://api.github.com/repos/{org}/{repo}/compare/{base}...{head}",
-        org = params.org,
-        repo = params.repo,
+    let github_compare_path = format!(
+        "/repos/{org}/{repo}/compare/{base}...{head}",
+        org = params.auth.org,
+        repo = params.auth.repo,
         base = params.base,
         head = params.head
     );
 
-    let jwt = generate_jwt(params.private_key, params.app_id)?;
-    let access_token = create_access_token_for_install(CreateAccessTokenForInstall {
-        jwt: &jwt,
-        install_id: params.install_id,
+    let get_page = |page: i64| -> Result<CommitComparison, GitHubError> {
+        let res = send_as_installation(client, &params.auth, |token| {
+            client
+                .get(&github_compare_path)
+                .query(&[("per_page", COMPARE_PAGE_SIZE), ("page", page)])
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+        })?;
+        res.error_for_status_ref()?;
+        Ok(res.json::<CommitComparison>()?)
+    };
+
+    // the commits of a comparison are paginated, but the other fields are
+    // the same on every page.
+    let mut comparison = get_page(1)?;
+    let mut page = 1;
+    while (comparison.commits.len() as i64) < comparison.total_commits && page < MAX_COMPARE_PAGES {
+        page += 1;
+        let next = get_page(page)?;
+        if next.commits.is_empty() {
+            break;
+        }
+        comparison.commits.extend(next.commits);
+    }
+    Ok(comparison)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequest {
+    pub number: i64,
+    pub title: String,
+    pub html_url: String,
+    pub user: Actor,
+    pub merged_at: Option<String>,
+}
+
+/// List the pull requests a commit belongs to.
+/// https://developer.github.com/v3/repos/commits/#list-pull-requests-associated-with-a-commit
+pub fn list_commit_pull_requests(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    sha: &str,
+) -> Result<Vec<PullRequest>, GitHubError> {
+    let res = send_as_installation_accepting(
+        client,
+        auth,
+        "application/vnd.github.groot-preview+json",
+        |token| {
+            client
+                .get(&format!(
+                    "/repos/{org}/{repo}/commits/{sha}/pulls",
+                    org = auth.org,
+                    repo = auth.repo,
+                    sha = sha
+                ))
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+        },
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<PullRequest>>()?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequestCommit {
+    pub sha: String,
+}
+
+/// List the first 100 commits of a pull request.
+/// https://developer.github.com/v3/pulls/#list-commits-on-a-pull-request
+pub fn list_pull_request_commits(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+) -> Result<Vec<PullRequestCommit>, GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .get(&format!(
+                "/repos/{org}/{repo}/pulls/{number}/commits?per_page=100",
+                org = auth.org,
+                repo = auth.repo,
+                number = number
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
     })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<PullRequestCommit>>()?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PullRequestDetail {
+    pub merged_by: Option<Actor>,
+}
+
+/// https://developer.github.com/v3/pulls/#get-a-pull-request
+pub fn get_pull_request(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+) -> Result<PullRequestDetail, GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .get(&format!(
+                "/repos/{org}/{repo}/pulls/{number}",
+                org = auth.org,
+                repo = auth.repo,
+                number = number
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<PullRequestDetail>()?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Review {
+    /// `None` for reviewers whose account was deleted.
+    pub user: Option<Actor>,
+    /// One of `APPROVED`, `CHANGES_REQUESTED`, `COMMENTED` or `DISMISSED`.
+    pub state: String,
+}
+
+/// https://developer.github.com/v3/pulls/reviews/#list-reviews-for-a-pull-request
+pub fn list_pull_request_reviews(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+) -> Result<Vec<Review>, GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .get(&format!(
+                "/repos/{org}/{repo}/pulls/{number}/reviews",
+                org = auth.org,
+                repo = auth.repo,
+                number = number
+            ))
+            .query(&[("per_page", 100)])
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<Review>>()?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IssueComment {
+    pub id: i64,
+}
+
+/// Comment on an issue or pull request.
+/// https://developer.github.com/v3/issues/comments/#create-an-issue-comment
+pub fn create_issue_comment(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+    body: &str,
+) -> Result<IssueComment, GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .post(&format!(
+                "/repos/{org}/{repo}/issues/{number}/comments",
+                org = auth.org,
+                repo = auth.repo,
+                number = number
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&json!({ "body": body }))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<IssueComment>()?)
+}
+
+/// https://developer.github.com/v3/issues/comments/#update-an-issue-comment
+pub fn update_issue_comment(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    comment_id: i64,
+    body: &str,
+) -> Result<IssueComment, GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .patch(&format!(
+                "/repos/{org}/{repo}/issues/comments/{comment_id}",
+                org = auth.org,
+                repo = auth.repo,
+                comment_id = comment_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&json!({ "body": body }))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<IssueComment>()?)
+}
+
+/// Percent-encode a value for use as a URL path segment.
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Add a label to an issue or pull request, creating the label if the
+/// repository doesn't have it.
+/// https://developer.github.com/v3/issues/labels/#add-labels-to-an-issue
+pub fn add_label(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+    label: &str,
+) -> Result<(), GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .post(&format!(
+                "/repos/{org}/{repo}/issues/{number}/labels",
+                org = auth.org,
+                repo = auth.repo,
+                number = number
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&json!({ "labels": [label] }))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(())
+}
+
+/// Remove a label from an issue or pull request. Removing a label the issue
+/// doesn't have succeeds.
+/// https://developer.github.com/v3/issues/labels/#remove-a-label-from-an-issue
+pub fn remove_label(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    number: i64,
+    label: &str,
+) -> Result<(), GitHubError> {
+    let res = send_as_installation(client, auth, |token| {
+        client
+            .delete(&format!(
+                "/repos/{org}/{repo}/issues/{number}/labels/{label}",
+                org = auth.org,
+                repo = auth.repo,
+                number = number,
+                label = encode_path_segment(label)
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+    })?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    res.error_for_status_ref()?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Deployment {
+    pub id: i64,
+}
+
+/// Deployment statuses other than `success`, `failure` and `error` need the
+/// flash and ant-man previews.
+const DEPLOYMENTS_ACCEPT: &str =
+    "application/vnd.github.flash-preview+json, application/vnd.github.ant-man-preview+json";
+
+/// List deployments of a commit to an environment, newest first.
+/// https://developer.github.com/v3/repos/deployments/#list-deployments
+pub fn list_deployments(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    sha: &str,
+    environment: &str,
+) -> Result<Vec<Deployment>, GitHubError> {
+    let res = send_as_installation_accepting(client, auth, DEPLOYMENTS_ACCEPT, |token| {
+        client
+            .get(&format!(
+                "/repos/{org}/{repo}/deployments",
+                org = auth.org,
+                repo = auth.repo
+            ))
+            .query(&[("sha", sha), ("environment", environment)])
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<Deployment>>()?)
+}
+
+pub struct CreateDeployment<'a> {
+    pub sha: &'a str,
+    pub environment: &'a str,
+    pub description: &'a str,
+}
+
+/// https://developer.github.com/v3/repos/deployments/#create-a-deployment
+pub fn create_deployment(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    params: CreateDeployment,
+) -> Result<Deployment, GitHubError> {
+    let res = send_as_installation_accepting(client, auth, DEPLOYMENTS_ACCEPT, |token| {
+        client
+            .post(&format!(
+                "/repos/{org}/{repo}/deployments",
+                org = auth.org,
+                repo = auth.repo
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&json!({
+                "ref": params.sha,
+                "environment": params.environment,
+                "description": params.description,
+                // Heroku already deployed the commit, so don't merge the
+                // default branch into it or wait for status checks.
+                "auto_merge": false,
+                "required_contexts": [],
+            }))
+    })?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Deployment>()?)
+}
 
-    let client = reqwest::blocking::Client::builder()
-        .user_agent("chdsbd/heroku-deploy-notifier")
-        .build()?;
-    let res = client
-        .get(&github_compare_url)
-        .header("Authorization", format!("Bearer {}", access_token.token))
-        .send()?;
+pub struct CreateDeploymentStatus<'a> {
+    pub deployment_id: i64,
+    /// One of `in_progress`, `success`, `failure` or `inactive`.
+    pub state: &'a str,
+    pub log_url: &'a str,
+    pub environment_url: &'a str,
+    pub description: &'a str,
+}
 
+/// Statuses of `success` mark the environment's previous deployments
+/// `inactive`.
+/// https://developer.github.com/v3/repos/deployments/#create-a-deployment-status
+pub fn create_deployment_status(
+    client: &GitHubClient,
+    auth: &RepoAuth,
+    params: CreateDeploymentStatus,
+) -> Result<(), GitHubError> {
+    let res = send_as_installation_accepting(client, auth, DEPLOYMENTS_ACCEPT, |token| {
+        client
+            .post(&format!(
+                "/repos/{org}/{repo}/deployments/{deployment_id}/statuses",
+                org = auth.org,
+                repo = auth.repo,
+                deployment_id = params.deployment_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .json(&json!({
+                "state": params.state,
+                "log_url": params.log_url,
+                "environment_url": params.environment_url,
+                "description": params.description,
+                "auto_inactive": true,
+            }))
+    })?;
     res.error_for_status_ref()?;
-    Ok(res.json::<CommitComparison>()?)
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn cache_with_token(expires_at: DateTime<Utc>) -> TokenCache {
+        let cache = TokenCache::default();
+        cache
+            .installations
+            .lock()
+            .unwrap()
+            .insert("acme-corp/blog".to_string(), 202154);
+        cache.tokens.lock().unwrap().insert(
+            202154,
+            GithubAccessToken {
+                expires_at,
+                permissions: Value::Null,
+                repository_selection: "all".to_string(),
+                token: "v1.cached".to_string(),
+            },
+        );
+        cache
+    }
+
+    #[test]
+    fn test_encode_path_segment() {
+        assert_eq!(
+            encode_path_segment("deployed:production"),
+            "deployed%3Aproduction"
+        );
+        assert_eq!(encode_path_segment("in prod/v2"), "in%20prod%2Fv2");
+        assert_eq!(
+            encode_path_segment("deployed-staging_1.0~"),
+            "deployed-staging_1.0~"
+        );
+    }
+
+    #[test]
+    fn test_co_author_emails() {
+        let message = "Add search to blog
+
+Co-authored-by: Christopher Dignam <chris@dignam.xyz>
+co-authored-by: ghost <1929960+ghost@users.noreply.github.com>
+Co-authored-by: no email";
+        assert_eq!(
+            co_author_emails(message),
+            vec!["chris@dignam.xyz", "1929960+ghost@users.noreply.github.com"]
+        );
+        assert!(co_author_emails("Add search to blog").is_empty());
+    }
+
+    #[test]
+    fn test_noreply_email_user_id() {
+        assert_eq!(
+            noreply_email_user_id("1929960+ghost@users.noreply.github.com"),
+            Some(1929960)
+        );
+        assert_eq!(
+            noreply_email_user_id("ghost@users.noreply.github.com"),
+            None
+        );
+        assert_eq!(
+            noreply_email_user_id("1929960+ghost@users.noreply.ghe.example.com"),
+            Some(1929960)
+        );
+        assert_eq!(noreply_email_user_id("chris@dignam.xyz"), None);
+    }
+
+    #[test]
+    fn test_token_cache() {
+        let client = ApiClient::new(
+            reqwest::blocking::Client::new(),
+            "http://localhost",
+            Duration::from_secs(0),
+        );
+        let params = RepoAuth {
+            private_key: "not a private key",
+            app_id: "1047",
+            org: "acme-corp",
+            repo: "blog",
+        };
+        let now = Utc.ymd(2020, 1, 1).and_hms(12, 0, 0);
+
+        let cache = cache_with_token(now + chrono::Duration::minutes(30));
+        assert_eq!(cache.get(&client, &params, now).unwrap(), "v1.cached");
+
+        // a token that expires soon is replaced, which fails with an invalid key.
+        let cache = cache_with_token(now + chrono::Duration::minutes(1));
+        assert!(matches!(
+            cache.get(&client, &params, now),
+            Err(GitHubError::JsonWebTokenCreation(_))
+        ));
+
+        let cache = cache_with_token(now + chrono::Duration::minutes(30));
+        cache.invalidate("acme-corp", "blog");
+        assert!(cache.get(&client, &params, now).is_err());
+    }
 }
@@ -1,5 +1,12 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
 use reqwest::header::{ACCEPT, AUTHORIZATION, RANGE};
-use serde::Deserialize;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::client::ApiClient;
+use crate::github::Repo;
 
 #[derive(Debug)]
 pub enum HerokuError {
@@ -18,40 +25,549 @@ pub struct HerokuReleaseSlug {
 }
 #[derive(Deserialize)]
 pub struct HerokuRelease {
-    pub slug: HerokuReleaseSlug,
-}
-
-pub fn get_release(app: &str, release: i64, token: &str) -> Result<HerokuRelease, HerokuError> {
-    let res = reqwest::blocking::Client::new()
-        .get(&format!(
-            "https://api.heroku.com/apps/{app}/releases/{release}",
-            app = app,
-            release = release
-        ))
-        .header("User-Agent", "chdsbd/eve")
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .header(ACCEPT, "application/vnd.heroku+json; version=3")
-        .header(RANGE, "version; order=desc")
-        .send()?;
+    pub version: i64,
+    /// One of `pending`, `succeeded` or `failed`. Releases whose release phase
+    /// failed keep their slug but never went live.
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    /// Releases that don't build code, like config var changes and add-on
+    /// attachments, have no slug.
+    pub slug: Option<HerokuReleaseSlug>,
+}
+
+/// Releases or builds listed per request when walking back through history.
+const RELEASE_PAGE_SIZE: i64 = 200;
+/// Pages of releases searched for the previous code release before giving up.
+const MAX_RELEASE_PAGES: usize = 10;
+
+/// A page of releases and the `Range` of the next page, if there is one.
+pub struct HerokuReleasePage {
+    pub releases: Vec<HerokuRelease>,
+    pub next_range: Option<String>,
+}
+
+/// List releases in `range`, like `version ..42; order=desc, max=200`.
+/// https://devcenter.heroku.com/articles/platform-api-reference#release-list
+/// https://devcenter.heroku.com/articles/platform-api-reference#ranges
+pub fn list_releases(
+    client: &ApiClient,
+    app: &str,
+    range: &str,
+    token: &str,
+) -> Result<HerokuReleasePage, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!("/apps/{app}/releases", app = app))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3")
+            .header(RANGE, range),
+    )?;
     res.error_for_status_ref()?;
-    Ok(res.json::<HerokuRelease>()?)
+    // Heroku responds with `206 Partial Content` and a `Next-Range` header
+    // while there are more releases.
+    let next_range = if res.status() == StatusCode::PARTIAL_CONTENT {
+        res.headers()
+            .get("next-range")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    } else {
+        None
+    };
+    Ok(HerokuReleasePage {
+        releases: res.json::<Vec<HerokuRelease>>()?,
+        next_range,
+    })
+}
+
+/// Find the slug of the newest successful release before `version` in
+/// `releases`.
+fn previous_release_slug(releases: Vec<HerokuRelease>, version: i64) -> Option<HerokuReleaseSlug> {
+    releases
+        .into_iter()
+        .filter(|release| release.version < version && release.status == "succeeded")
+        .find_map(|release| release.slug)
+}
+
+/// Find the slug of the most recent successful release before `version` that
+/// deployed code.
+pub fn get_previous_release_slug(
+    client: &ApiClient,
+    app: &str,
+    version: i64,
+    token: &str,
+) -> Result<Option<HerokuReleaseSlug>, HerokuError> {
+    let mut range = format!(
+        "version ..{version}; order=desc, max={max}",
+        version = version - 1,
+        max = RELEASE_PAGE_SIZE
+    );
+    for _ in 0..MAX_RELEASE_PAGES {
+        let page = list_releases(client, app, &range, token)?;
+        let slug = previous_release_slug(page.releases, version);
+        if slug.is_some() {
+            return Ok(slug);
+        }
+        range = match page.next_range {
+            Some(next_range) => next_range,
+            None => return Ok(None),
+        };
+    }
+    eprintln!(
+        "no code release of {} found in the {} releases before v{}",
+        app,
+        MAX_RELEASE_PAGES as i64 * RELEASE_PAGE_SIZE,
+        version
+    );
+    Ok(None)
+}
+#[derive(Deserialize)]
+pub struct HerokuBuildSourceBlob {
+    pub url: Option<String>,
+    /// Commit the build was created from.
+    pub version: Option<String>,
+}
+#[derive(Deserialize)]
+pub struct HerokuBuild {
+    pub id: String,
+    /// One of `pending`, `succeeded` or `failed`.
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub source_blob: HerokuBuildSourceBlob,
+    pub output_stream_url: Option<String>,
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#build-info
+pub fn get_build(
+    client: &ApiClient,
+    app: &str,
+    build_id: &str,
+    token: &str,
+) -> Result<HerokuBuild, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!(
+                "/apps/{app}/builds/{build_id}",
+                app = app,
+                build_id = build_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3"),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<HerokuBuild>()?)
+}
+
+/// List the most recent builds of an app, newest first.
+/// https://devcenter.heroku.com/articles/platform-api-reference#build-list
+pub fn list_builds(
+    client: &ApiClient,
+    app: &str,
+    token: &str,
+) -> Result<Vec<HerokuBuild>, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!("/apps/{app}/builds", app = app))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3")
+            .header(
+                RANGE,
+                format!(
+                    "created_at ..; order=desc, max={max}",
+                    max = RELEASE_PAGE_SIZE
+                ),
+            ),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<HerokuBuild>>()?)
+}
+
+/// Find the commit of the most recent successful build created before `build`.
+pub fn get_previous_successful_build_commit(
+    client: &ApiClient,
+    app: &str,
+    build: &HerokuBuild,
+    token: &str,
+) -> Result<Option<String>, HerokuError> {
+    Ok(list_builds(client, app, token)?
+        .into_iter()
+        .filter(|previous| previous.status == "succeeded" && previous.created_at < build.created_at)
+        .find_map(|previous| previous.source_blob.version))
+}
+
+/// Parse the GitHub repository from a build's source URL.
+///
+/// Builds from Heroku's GitHub integration download the source tarball from
+/// GitHub, like `https://api.github.com/repos/acme-corp/blog/tarball/56b5150`.
+/// Builds from GitHub Enterprise Server are matched by its API and web URLs.
+fn github_repo_from_source_url(url: &str, github: &GitHubUrls) -> Option<Repo> {
+    let api_prefix = format!("{}/repos/", github.api_url.trim_end_matches('/'));
+    let web_prefix = format!("{}/", github.web_url.trim_end_matches('/'));
+    let path = url
+        .strip_prefix(api_prefix.as_str())
+        .or_else(|| url.strip_prefix("https://codeload.github.com/"))
+        .or_else(|| url.strip_prefix(web_prefix.as_str()))?;
+    let mut segments = path.split('/');
+    let org = segments.next()?;
+    let name = segments.next()?.split('?').next()?;
+    Repo::parse(&format!("{}/{}", org, name))
+}
+
+/// Base URLs of the GitHub instance apps are built from.
+pub struct GitHubUrls<'a> {
+    pub api_url: &'a str,
+    pub web_url: &'a str,
 }
+
+/// Find the GitHub repository an app was most recently built from.
+pub fn get_github_repo(
+    client: &ApiClient,
+    app: &str,
+    github: &GitHubUrls,
+    token: &str,
+) -> Result<Option<Repo>, HerokuError> {
+    Ok(list_builds(client, app, token)?
+        .into_iter()
+        .find_map(|build| {
+            build
+                .source_blob
+                .url
+                .as_deref()
+                .and_then(|url| github_repo_from_source_url(url, github))
+        }))
+}
+
 #[derive(Deserialize)]
 pub struct HerokuSlug {
     pub commit: String,
 }
 
-pub fn get_slug(app: &str, slug: &str, token: &str) -> Result<HerokuSlug, HerokuError> {
-    let res = reqwest::blocking::Client::new()
-        .get(&format!(
-            "https://api.heroku.com/apps/{app}/slugs/{slug}",
-            app = app,
-            slug = slug
-        ))
-        .header("User-Agent", "chdsbd/eve")
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .header(ACCEPT, "application/vnd.heroku+json; version=3")
-        .send()?;
+pub fn get_slug(
+    client: &ApiClient,
+    app: &str,
+    slug: &str,
+    token: &str,
+) -> Result<HerokuSlug, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!("/apps/{app}/slugs/{slug}", app = app, slug = slug))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3"),
+    )?;
     res.error_for_status_ref()?;
     Ok(res.json::<HerokuSlug>()?)
 }
+
+/// Heroku describes releases created by `heroku rollback` as "Rollback to vN".
+pub fn is_rollback(release_description: &str) -> bool {
+    release_description.starts_with("Rollback to ")
+}
+
+/// The release of another app in the pipeline that a release was promoted
+/// from.
+#[derive(Debug, PartialEq)]
+pub struct PromotionSource<'a> {
+    pub app: &'a str,
+    pub version: i64,
+}
+
+/// Get the source release of a pipeline promotion from a release description.
+///
+/// Heroku describes promoted releases as "Promote acme-staging v12 3f9a2c1".
+/// The description is free text, so callers confirm the source release with
+/// `get_release`.
+pub fn promotion_source(release_description: &str) -> Option<PromotionSource> {
+    let mut words = release_description
+        .strip_prefix("Promote ")?
+        .split_whitespace();
+    let app = words.next()?;
+    let version = words.next()?.strip_prefix('v')?.parse().ok()?;
+    Some(PromotionSource { app, version })
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#release-info
+pub fn get_release(
+    client: &ApiClient,
+    app: &str,
+    version: i64,
+    token: &str,
+) -> Result<HerokuRelease, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!(
+                "/apps/{app}/releases/{version}",
+                app = app,
+                version = version
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3"),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<HerokuRelease>()?)
+}
+
+#[derive(Deserialize)]
+pub struct HerokuWebhook {
+    pub id: String,
+    pub url: String,
+    /// Entities the webhook is subscribed to, like `api:release`.
+    pub include: Vec<String>,
+    /// Either `notify` or `sync`.
+    pub level: String,
+}
+
+#[derive(Serialize)]
+pub struct HerokuWebhookOpts<'a> {
+    pub include: &'a [&'a str],
+    pub level: &'a str,
+    pub url: &'a str,
+    /// Updates leave the secret unchanged when it's `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<&'a str>,
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#app-webhook-list
+pub fn list_webhooks(
+    client: &ApiClient,
+    app: &str,
+    token: &str,
+) -> Result<Vec<HerokuWebhook>, HerokuError> {
+    let res = client.send(
+        client
+            .get(&format!("/apps/{app}/webhooks", app = app))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3"),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<Vec<HerokuWebhook>>()?)
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#app-webhook-create
+pub fn create_webhook(
+    client: &ApiClient,
+    app: &str,
+    opts: &HerokuWebhookOpts,
+    token: &str,
+) -> Result<HerokuWebhook, HerokuError> {
+    let res = client.send(
+        client
+            .post(&format!("/apps/{app}/webhooks", app = app))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3")
+            .json(opts),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<HerokuWebhook>()?)
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#app-webhook-update
+pub fn update_webhook(
+    client: &ApiClient,
+    app: &str,
+    webhook_id: &str,
+    opts: &HerokuWebhookOpts,
+    token: &str,
+) -> Result<HerokuWebhook, HerokuError> {
+    let res = client.send(
+        client
+            .patch(&format!(
+                "/apps/{app}/webhooks/{webhook_id}",
+                app = app,
+                webhook_id = webhook_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3")
+            .json(opts),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(res.json::<HerokuWebhook>()?)
+}
+
+/// https://devcenter.heroku.com/articles/platform-api-reference#app-webhook-delete
+pub fn delete_webhook(
+    client: &ApiClient,
+    app: &str,
+    webhook_id: &str,
+    token: &str,
+) -> Result<(), HerokuError> {
+    let res = client.send(
+        client
+            .delete(&format!(
+                "/apps/{app}/webhooks/{webhook_id}",
+                app = app,
+                webhook_id = webhook_id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(ACCEPT, "application/vnd.heroku+json; version=3"),
+    )?;
+    res.error_for_status_ref()?;
+    Ok(())
+}
+
+/// Check the `Heroku-Webhook-Hmac-SHA256` header of a webhook delivery.
+///
+/// The signature is accepted if it matches any of `secrets`, which allows a
+/// new secret to be configured before the old one is removed.
+/// https://devcenter.heroku.com/articles/app-webhooks#securing-webhook-requests
+pub fn verify_webhook_signature<'a>(
+    secrets: impl IntoIterator<Item = &'a String>,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let signature = match base64::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    secrets.into_iter().any(|secret| {
+        let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+        mac.update(body);
+        mac.verify(&signature).is_ok()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BODY: &[u8] = br#"{"action":"update"}"#;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.update(body);
+        base64::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_previous_release_slug() {
+        let release = |version, status: &str, slug: Option<&str>| HerokuRelease {
+            version,
+            status: status.to_string(),
+            created_at: Utc.ymd(2020, 6, 1).and_hms(14, 2, 0),
+            slug: slug.map(|id| HerokuReleaseSlug { id: id.to_string() }),
+        };
+        let releases = vec![
+            release(42, "succeeded", Some("slug-42")),
+            release(41, "failed", Some("slug-41")),
+            release(40, "succeeded", None),
+            release(39, "succeeded", Some("slug-39")),
+        ];
+        assert_eq!(
+            previous_release_slug(releases, 42).map(|slug| slug.id),
+            Some("slug-39".to_string()),
+            "skips the failed release and the config change"
+        );
+        assert!(previous_release_slug(vec![release(41, "failed", Some("slug-41"))], 42).is_none());
+    }
+
+    #[test]
+    fn test_promotion_source() {
+        assert_eq!(
+            promotion_source("Promote acme-staging v12 3f9a2c1"),
+            Some(PromotionSource {
+                app: "acme-staging",
+                version: 12
+            })
+        );
+        assert_eq!(promotion_source("Deploy 3f9a2c1"), None);
+        assert_eq!(promotion_source("Rollback to v11"), None);
+        assert_eq!(promotion_source("Promote acme-staging"), None);
+        assert_eq!(promotion_source("Promote acme-staging release 12"), None);
+        assert_eq!(promotion_source("Promoted acme-staging v12 3f9a2c1"), None);
+    }
+    const GITHUB: GitHubUrls = GitHubUrls {
+        api_url: "https://api.github.com",
+        web_url: "https://github.com",
+    };
+
+    #[test]
+    fn test_github_repo_from_source_url() {
+        let expected = Repo::parse("acme-corp/blog");
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://api.github.com/repos/acme-corp/blog/tarball/56b515000c090c0ba5f285c6e19f9451788413f1",
+                &GITHUB
+            ),
+            expected
+        );
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://codeload.github.com/acme-corp/blog/legacy.tar.gz/master",
+                &GITHUB
+            ),
+            expected
+        );
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://s3-external-1.amazonaws.com/heroku-sources/blog.tgz",
+                &GITHUB
+            ),
+            None
+        );
+    }
+    #[test]
+    fn test_github_enterprise_repo_from_source_url() {
+        let github = GitHubUrls {
+            api_url: "https://ghe.example.com/api/v3/",
+            web_url: "https://ghe.example.com",
+        };
+        let expected = Repo::parse("acme-corp/blog");
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://ghe.example.com/api/v3/repos/acme-corp/blog/tarball/56b515000c090c0ba5f285c6e19f9451788413f1",
+                &github
+            ),
+            expected
+        );
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://ghe.example.com/acme-corp/blog/archive/master.tar.gz",
+                &github
+            ),
+            expected
+        );
+        assert_eq!(
+            github_repo_from_source_url(
+                "https://api.github.com/repos/acme-corp/blog/tarball/master",
+                &github
+            ),
+            None
+        );
+    }
+    #[test]
+    fn test_valid_signature() {
+        let secrets = vec!["old-secret".to_string(), "new-secret".to_string()];
+        assert!(verify_webhook_signature(
+            &secrets,
+            BODY,
+            &sign("new-secret", BODY)
+        ));
+        assert!(verify_webhook_signature(
+            &secrets,
+            BODY,
+            &sign("old-secret", BODY)
+        ));
+    }
+    #[test]
+    fn test_invalid_signature() {
+        let secrets = vec!["new-secret".to_string()];
+        assert!(!verify_webhook_signature(
+            &secrets,
+            BODY,
+            &sign("other-secret", BODY)
+        ));
+        assert!(!verify_webhook_signature(
+            &secrets,
+            br#"{"action":"create"}"#,
+            &sign("new-secret", BODY)
+        ));
+        assert!(!verify_webhook_signature(&secrets, BODY, "not base64!"));
+        assert!(!verify_webhook_signature(
+            &Vec::new(),
+            BODY,
+            &sign("", BODY)
+        ));
+    }
+}
@@ -1,9 +1,75 @@
+use chrono::{DateTime, Utc};
 use rocket::config::{Config, Environment};
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, LenientForm, Request};
+use rocket::response::{self, Responder, Response};
 use rocket::State;
 use rocket_contrib::json::Json;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use serde_json::{json, Value};
 
-use crate::heroku;
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::client::Clients;
+use crate::jobs::Job;
+use crate::queue::{Delivery, Queue, Subject};
+use crate::store::Store;
+use crate::{github, heroku, slack, EveError};
+
+/// Response for a failed upstream API request. Upstream failures are reported
+/// as a `502` with the upstream status code in the body.
+fn upstream_error(upstream: &str, e: &reqwest::Error) -> (Status, Value) {
+    (
+        Status::BadGateway,
+        json!({
+            "type": "upstream_error",
+            "upstream": upstream,
+            "upstream_status": e.status().map(|status| status.as_u16()),
+            "message": e.to_string(),
+        }),
+    )
+}
+
+fn error_response(error: &EveError) -> (Status, Value) {
+    match error {
+        EveError::Unauthorized(message) => (
+            Status::Unauthorized,
+            json!({ "type": "unauthorized", "message": message }),
+        ),
+        EveError::InvalidPayload(message) => (
+            Status::UnprocessableEntity,
+            json!({ "type": "invalid_payload", "message": message }),
+        ),
+        EveError::HerokuError(heroku::HerokuError::HttpError(e)) => upstream_error("heroku", e),
+        EveError::GitHubError(github::GitHubError::HttpError(e)) => upstream_error("github", e),
+        EveError::SlackError(slack::SlackError::HttpError(e)) => upstream_error("slack", e),
+        EveError::GitHubError(github::GitHubError::JsonWebTokenCreation(e)) => (
+            Status::InternalServerError,
+            json!({ "type": "internal_error", "message": e.to_string() }),
+        ),
+        EveError::StoreError(e) => (
+            Status::InternalServerError,
+            json!({ "type": "internal_error", "message": format!("{:?}", e) }),
+        ),
+        EveError::InternalError(message) => (
+            Status::InternalServerError,
+            json!({ "type": "internal_error", "message": message }),
+        ),
+    }
+}
+
+impl<'r> Responder<'r> for EveError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let (status, error) = error_response(&self);
+        Response::build_from(Json(json!({ "error": error })).respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
 
 #[get("/")]
 const fn root() -> &'static str {
@@ -11,7 +77,7 @@ const fn root() -> &'static str {
 }
 
 #[derive(Deserialize, Debug)]
-struct WebhookReleaseApp {
+struct WebhookApp {
     name: String,
 }
 
@@ -22,66 +88,389 @@ struct WebhookReleaseEventSlug {
 }
 #[derive(Deserialize, Debug)]
 struct WebhookReleaseEventData {
-    app: WebhookReleaseApp,
-    slug: WebhookReleaseEventSlug,
+    app: WebhookApp,
+    /// `None` for releases that don't deploy code, like config var changes.
+    slug: Option<WebhookReleaseEventSlug>,
     current: bool,
     version: i64,
+    description: String,
+    /// One of `pending`, `succeeded` or `failed`. Releases are pending while
+    /// the release phase runs.
+    status: String,
+    output_stream_url: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+struct WebhookBuildEventData {
+    id: String,
+    app: WebhookApp,
+    status: String,
+}
+#[derive(Deserialize, Debug)]
+struct WebhookDynoRelease {
+    version: i64,
 }
 #[derive(Deserialize, Debug)]
-struct WebhookReleaseEvent {
+struct WebhookDynoEventData {
+    app: WebhookApp,
+    /// Like `web.1`.
+    name: String,
+    state: String,
+    release: Option<WebhookDynoRelease>,
+    updated_at: DateTime<Utc>,
+}
+#[derive(Deserialize, Debug)]
+struct WebhookEventBody<T> {
+    id: String,
     action: String,
-    data: WebhookReleaseEventData,
+    data: T,
+}
+/// https://devcenter.heroku.com/articles/webhook-events
+#[derive(Deserialize, Debug)]
+#[serde(tag = "resource", rename_all = "snake_case")]
+enum WebhookEvent {
+    Release(WebhookEventBody<WebhookReleaseEventData>),
+    Build(WebhookEventBody<WebhookBuildEventData>),
+    Dyno(WebhookEventBody<WebhookDynoEventData>),
+    #[serde(other)]
+    Other,
+}
+
+/// Check the legacy `auth_token` query parameter against the configured secret.
+fn is_valid_auth_token(request: &Request, config: &crate::cli::Opt) -> bool {
+    match (
+        &config.secret,
+        request.get_query_value::<String>("auth_token"),
+    ) {
+        (Some(secret), Some(Ok(auth_token))) => &auth_token == secret,
+        _ => false,
+    }
+}
+
+/// Request guard for endpoints that only support `auth_token` authentication.
+struct LegacyAuth;
+
+impl<'a, 'r> FromRequest<'a, 'r> for LegacyAuth {
+    type Error = EveError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<crate::cli::Opt>>() {
+            Outcome::Success(config) => config,
+            _ => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    EveError::InternalError("missing configuration".to_string()),
+                ))
+            }
+        };
+        if is_valid_auth_token(request, &config) {
+            Outcome::Success(Self)
+        } else {
+            Outcome::Failure((
+                Status::Unauthorized,
+                EveError::Unauthorized("invalid auth_token".to_string()),
+            ))
+        }
+    }
+}
+
+/// Largest webhook body we will read into memory.
+const WEBHOOK_BODY_LIMIT: u64 = 1024 * 1024;
+
+/// A Heroku webhook payload from an authenticated request.
+///
+/// Requests are authenticated by the `Heroku-Webhook-Hmac-SHA256` header. If a
+/// legacy `secret` is configured, the `auth_token` query parameter is accepted
+/// as well.
+struct HerokuWebhook<T>(T);
+
+impl<T: DeserializeOwned> FromDataSimple for HerokuWebhook<T> {
+    type Error = EveError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<crate::cli::Opt>>() {
+            Outcome::Success(config) => config,
+            _ => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    EveError::InternalError("missing configuration".to_string()),
+                ))
+            }
+        };
+
+        let mut body = Vec::new();
+        if let Err(e) = data.open().take(WEBHOOK_BODY_LIMIT).read_to_end(&mut body) {
+            return Outcome::Failure((
+                Status::UnprocessableEntity,
+                EveError::InvalidPayload(e.to_string()),
+            ));
+        }
+
+        let signature = request.headers().get_one("Heroku-Webhook-Hmac-SHA256");
+        let signature_valid = signature.map_or(false, |signature| {
+            heroku::verify_webhook_signature(&config.heroku_webhook_secrets, &body, signature)
+        });
+        if !signature_valid && !is_valid_auth_token(request, &config) {
+            return Outcome::Failure((
+                Status::Unauthorized,
+                EveError::Unauthorized("invalid or missing webhook signature".to_string()),
+            ));
+        }
+
+        match serde_json::from_slice(&body) {
+            Ok(payload) => Outcome::Success(Self(payload)),
+            Err(e) => Outcome::Failure((
+                Status::UnprocessableEntity,
+                EveError::InvalidPayload(e.to_string()),
+            )),
+        }
+    }
 }
 
 #[post(
-    "/heroku_webhook?<auth_token>&<github_org_name>&<github_repo_name>",
+    "/heroku_webhook?<github_org_name>&<github_repo_name>",
     data = "<task>"
 )]
 fn heroku_webhook(
-    task: Json<WebhookReleaseEvent>,
-    auth_token: String,
-    github_org_name: String,
-    github_repo_name: String,
+    task: Result<HerokuWebhook<WebhookEvent>, EveError>,
+    github_org_name: Option<String>,
+    github_repo_name: Option<String>,
+    queue: State<Queue>,
     config: State<crate::cli::Opt>,
-) -> Result<(), crate::EveError> {
-    if auth_token != config.secret {
-        return Err(crate::EveError::InternalError("invalid auth".to_string()));
+) -> Result<Status, EveError> {
+    let HerokuWebhook(task) = task?;
+    let enqueued = match task {
+        WebhookEvent::Release(event) => {
+            enqueue_release(event, github_org_name, github_repo_name, &queue, &config)?
+        }
+        WebhookEvent::Build(event) => {
+            enqueue_build(event, github_org_name, github_repo_name, &queue)?
+        }
+        WebhookEvent::Dyno(event) => {
+            enqueue_dyno_crash(event, github_org_name, github_repo_name, &queue)?
+        }
+        WebhookEvent::Other => false,
+    };
+    Ok(if enqueued {
+        Status::Accepted
+    } else {
+        Status::Ok
+    })
+}
+
+/// Queue a job to notify authors of a release. Returns `false` if the event
+/// was ignored.
+fn enqueue_release(
+    event: WebhookEventBody<WebhookReleaseEventData>,
+    github_org: Option<String>,
+    github_repo: Option<String>,
+    queue: &Queue,
+    config: &crate::cli::Opt,
+) -> Result<bool, EveError> {
+    if event.action != "update" {
+        return Ok(false);
+    }
+    let slug = match &event.data.slug {
+        Some(slug) => slug,
+        None => return Ok(false),
+    };
+    let release = format!("v{}", event.data.version);
+    let promotion_source = heroku::promotion_source(&event.data.description);
+    if promotion_source.is_none() && event.data.description.starts_with("Promote") {
+        eprintln!(
+            "could not parse promotion source of {} {} from {:?}, notifying as a release",
+            event.data.app.name, release, event.data.description
+        );
+    }
+    let status = event.data.status.as_str();
+    let (subject, mut jobs) = match status {
+        "failed" => (
+            Subject::Release(&release),
+            vec![Job::ReleaseFailed {
+                app: event.data.app.name.clone(),
+                version: event.data.version,
+                head: slug.commit.clone(),
+                output_stream_url: event.data.output_stream_url.clone(),
+                github_org: github_org.clone(),
+                github_repo: github_repo.clone(),
+            }],
+        ),
+        "succeeded" if event.data.current => (
+            Subject::Release(&release),
+            vec![Job::Release {
+                app: event.data.app.name.clone(),
+                version: event.data.version,
+                head: slug.commit.clone(),
+                rollback: heroku::is_rollback(&event.data.description),
+                promoted_from: promotion_source
+                    .as_ref()
+                    .map(|source| source.app.to_string()),
+                promoted_from_version: promotion_source.as_ref().map(|source| source.version),
+                github_org: github_org.clone(),
+                github_repo: github_repo.clone(),
+            }],
+        ),
+        // pending releases are still running their release phase, which is
+        // only tracked as a GitHub deployment.
+        "pending" if config.github_deployments => (Subject::PendingRelease(&release), vec![]),
+        _ => return Ok(false),
+    };
+    // deployment statuses are separate jobs, so a failing status doesn't
+    // hold up notifying the authors.
+    if config.github_deployments {
+        let (state, output_stream_url) = match status {
+            "pending" => ("in_progress", event.data.output_stream_url.clone()),
+            "failed" => ("failure", event.data.output_stream_url.clone()),
+            _ => ("success", None),
+        };
+        jobs.push(Job::RecordDeployment {
+            app: event.data.app.name.clone(),
+            release: release.clone(),
+            head: slug.commit.clone(),
+            state: state.to_string(),
+            output_stream_url,
+            github_org,
+            github_repo,
+        });
+    }
+    let delivery = Delivery {
+        event_id: Some(&event.id),
+        app: &event.data.app.name,
+        subject: Some(subject),
+    };
+    Ok(queue.enqueue_once(&jobs, &delivery)?)
+}
+
+/// Queue a job to notify authors of a failed build. Returns `false` if the
+/// event was ignored.
+fn enqueue_build(
+    event: WebhookEventBody<WebhookBuildEventData>,
+    github_org: Option<String>,
+    github_repo: Option<String>,
+    queue: &Queue,
+) -> Result<bool, EveError> {
+    if event.action != "update" || event.data.status != "failed" {
+        return Ok(false);
     }
+    let delivery = Delivery {
+        event_id: Some(&event.id),
+        app: &event.data.app.name,
+        subject: Some(Subject::Build(&event.data.id)),
+    };
+    let job = Job::BuildFailed {
+        app: event.data.app.name.clone(),
+        build_id: event.data.id.clone(),
+        github_org,
+        github_repo,
+    };
+    Ok(queue.enqueue_once(&[job], &delivery)?)
+}
 
-    if task.action != "update" || !task.data.current {
-        return Ok(());
+/// Queue a job to warn authors of a release when one of its dynos crashes.
+/// Returns `false` if the event was ignored.
+fn enqueue_dyno_crash(
+    event: WebhookEventBody<WebhookDynoEventData>,
+    github_org: Option<String>,
+    github_repo: Option<String>,
+    queue: &Queue,
+) -> Result<bool, EveError> {
+    if event.data.state != "crashed" {
+        return Ok(false);
     }
-    let release = task.data.version;
-    let app = &task.data.app.name;
-    let heroku_token = &config.heroku_token;
-    let base_ref = heroku::get_slug(
-        app,
-        &heroku::get_release(app, release - 1, heroku_token)?.slug.id,
-        heroku_token,
-    )?
-    .commit;
-
-    let head_ref = &task.data.slug.commit;
-
-    Ok(crate::handle_post_deploy_event(
-        crate::HandlePostDeployEvent {
-            github_app_private_key: &config.github_app_private_key,
-            github_app_id: &config.github_app_id,
-            github_app_install_id: &config.github_app_install_id,
-            github_org: &github_org_name,
-            github_repo: &github_repo_name,
-            github_ref_base: &base_ref,
-            github_ref_head: head_ref,
-            github_slack_users: &config.github_slack_user_ids,
-            slack_oauth_token: &config.slack_oauth_token,
-            heroku_release: &format!("v{}", release),
-            heroku_app_name: app,
-            now: chrono::Utc::now().into(),
-        },
-    )?)
+    let version = match &event.data.release {
+        Some(release) => release.version,
+        None => return Ok(false),
+    };
+    // every crash is its own event, so key the delivery on the event alone.
+    let delivery = Delivery {
+        event_id: Some(&event.id),
+        app: &event.data.app.name,
+        subject: None,
+    };
+    let job = Job::DynoCrashed {
+        app: event.data.app.name.clone(),
+        version,
+        dyno: event.data.name.clone(),
+        crashed_at: event.data.updated_at.timestamp(),
+        github_org,
+        github_repo,
+    };
+    Ok(queue.enqueue_once(&[job], &delivery)?)
 }
 
-pub fn start_server(opt: crate::cli::Opt) {
+/// Heroku HTTP post-deploy hook payload.
+///
+/// Heroku also sends `head`, `user`, `url` and `git_log`, which we don't use.
+/// https://devcenter.heroku.com/articles/deploy-hooks#http-post-hook
+#[derive(FromForm, Debug)]
+struct DeployHook {
+    app: String,
+    release: String,
+    head_long: String,
+    prev_head: String,
+}
+
+/// Endpoint for the legacy Heroku Deploy Hooks add-on.
+///
+/// Deploy hooks cannot be signed, so requests are authenticated with the
+/// `auth_token` query parameter.
+#[post(
+    "/heroku_deploy_hook?<github_org_name>&<github_repo_name>",
+    data = "<hook>"
+)]
+fn heroku_deploy_hook(
+    auth: Result<LegacyAuth, EveError>,
+    hook: LenientForm<DeployHook>,
+    github_org_name: Option<String>,
+    github_repo_name: Option<String>,
+    queue: State<Queue>,
+    config: State<crate::cli::Opt>,
+) -> Result<Status, EveError> {
+    auth?;
+    // the first deploy of an app has no previous commit to compare against.
+    if hook.prev_head.is_empty() {
+        return Ok(Status::Ok);
+    }
+    let delivery = Delivery {
+        event_id: None,
+        app: &hook.app,
+        subject: Some(Subject::Release(&hook.release)),
+    };
+    let mut jobs = vec![Job::Deploy {
+        app: hook.app.clone(),
+        release: hook.release.clone(),
+        base: hook.prev_head.clone(),
+        head: hook.head_long.clone(),
+        github_org: github_org_name.clone(),
+        github_repo: github_repo_name.clone(),
+    }];
+    if config.github_deployments {
+        jobs.push(Job::RecordDeployment {
+            app: hook.app.clone(),
+            release: hook.release.clone(),
+            head: hook.head_long.clone(),
+            state: "success".to_string(),
+            output_stream_url: None,
+            github_org: github_org_name,
+            github_repo: github_repo_name,
+        });
+    }
+    if !queue.enqueue_once(&jobs, &delivery)? {
+        return Ok(Status::Ok);
+    }
+    Ok(Status::Accepted)
+}
+
+/// Fallback for requests rejected before reaching a handler, like form bodies
+/// missing required fields.
+#[catch(422)]
+fn unprocessable_entity() -> Json<Value> {
+    Json(json!({
+        "error": { "type": "invalid_payload", "message": "could not parse request body" }
+    }))
+}
+
+/// Build the server and start the workers that process its job queue.
+pub fn rocket(opt: crate::cli::Opt) -> rocket::Rocket {
     let env = if opt.debug {
         Environment::Development
     } else {
@@ -89,8 +478,36 @@ pub fn start_server(opt: crate::cli::Opt) {
     };
     let mut config = Config::new(env);
     config.port = opt.port;
+    let store = Store::open(&opt.database_path).expect("could not open database");
+    let queue = Queue::new(store);
+    crate::queue::start_workers(&queue, &Arc::new(opt.clone()), &Clients::new(&opt));
     rocket::custom(config)
-        .mount("/", routes![root, heroku_webhook])
+        .mount("/", routes![root, heroku_webhook, heroku_deploy_hook])
+        .register(catchers![unprocessable_entity])
         .manage(opt)
-        .launch();
+        .manage(queue)
+}
+
+pub fn start_server(opt: crate::cli::Opt) {
+    rocket(opt).launch();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let (status, body) = error_response(&EveError::Unauthorized("invalid auth".to_string()));
+        assert_eq!(status, Status::Unauthorized);
+        assert_eq!(
+            body,
+            json!({ "type": "unauthorized", "message": "invalid auth" })
+        );
+
+        let (status, body) =
+            error_response(&EveError::InvalidPayload("missing field `id`".to_string()));
+        assert_eq!(status, Status::UnprocessableEntity);
+        assert_eq!(body["type"], "invalid_payload");
+    }
 }
@@ -0,0 +1,550 @@
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Opt;
+use crate::client::Clients;
+use crate::github::Repo;
+use crate::store::Store;
+use crate::{github, heroku, EveError, NotificationKind};
+
+/// Work queued by the HTTP endpoints and run by the queue workers.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Job {
+    /// A release from a Heroku `api:release` webhook. The base commit is looked
+    /// up from the previous release when the job runs.
+    Release {
+        app: String,
+        version: i64,
+        head: String,
+        /// Heroku described the release as a rollback.
+        #[serde(default)]
+        rollback: bool,
+        /// App the release was promoted from, for pipeline promotions.
+        #[serde(default)]
+        promoted_from: Option<String>,
+        /// Version of the release of `promoted_from` that was promoted.
+        #[serde(default)]
+        promoted_from_version: Option<i64>,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+    /// A release whose release phase failed, from a Heroku `api:release`
+    /// webhook.
+    ReleaseFailed {
+        app: String,
+        version: i64,
+        head: String,
+        output_stream_url: Option<String>,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+    /// A failed build from a Heroku `api:build` webhook.
+    BuildFailed {
+        app: String,
+        build_id: String,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+    /// A crashed dyno from a Heroku `api:dyno` webhook.
+    DynoCrashed {
+        app: String,
+        version: i64,
+        dyno: String,
+        /// Unix timestamp of the crash.
+        crashed_at: i64,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+    /// A deploy where both commits are already known, like from a Heroku
+    /// post-deploy hook.
+    Deploy {
+        app: String,
+        release: String,
+        base: String,
+        head: String,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+    /// A GitHub deployment status of a release, queued with the release's
+    /// other jobs when GitHub deployments are enabled. The statuses of a
+    /// release are recorded in the order they were queued.
+    RecordDeployment {
+        app: String,
+        release: String,
+        head: String,
+        /// One of `in_progress`, `success` or `failure`.
+        state: String,
+        /// Release phase output, linked instead of the release log.
+        output_stream_url: Option<String>,
+        #[serde(default)]
+        github_org: Option<String>,
+        #[serde(default)]
+        github_repo: Option<String>,
+    },
+}
+
+impl Job {
+    /// Jobs with the same ordering key run one at a time, in the order they
+    /// were queued.
+    pub fn ordering_key(&self) -> Option<String> {
+        match self {
+            Self::RecordDeployment { app, release, .. } => Some(format!("{}/{}", app, release)),
+            _ => None,
+        }
+    }
+}
+
+struct NotifyDeploy<'a> {
+    app: &'a str,
+    /// Release version, or the build id of failed builds.
+    release: &'a str,
+    kind: NotificationKind<'a>,
+    base: &'a str,
+    head: &'a str,
+    github_org: Option<&'a str>,
+    github_repo: Option<&'a str>,
+}
+
+/// Find the GitHub repository for an app, preferring the repository from the
+/// webhook URL, then configured mappings, then the app's GitHub builds.
+fn resolve_repo(
+    clients: &Clients,
+    app: &str,
+    github_org: Option<&str>,
+    github_repo: Option<&str>,
+    config: &Opt,
+) -> Result<Repo, EveError> {
+    if let (Some(org), Some(name)) = (github_org, github_repo) {
+        return Ok(Repo {
+            org: org.to_string(),
+            name: name.to_string(),
+        });
+    }
+    if let Some(repo) = config.heroku_app_github_repos.get(app) {
+        return Ok(repo.clone());
+    }
+    let github = heroku::GitHubUrls {
+        api_url: &config.github_api_url,
+        web_url: &config.github_web_url,
+    };
+    heroku::get_github_repo(&clients.heroku, app, &github, &config.heroku_token)?.ok_or_else(|| {
+        EveError::InternalError(format!(
+            "could not find GitHub repository for Heroku app `{}`",
+            app
+        ))
+    })
+}
+
+fn notify_deploy(
+    params: NotifyDeploy,
+    config: &Opt,
+    clients: &Clients,
+    store: &Store,
+) -> Result<(), EveError> {
+    let repo = resolve_repo(
+        clients,
+        params.app,
+        params.github_org,
+        params.github_repo,
+        config,
+    )?;
+    crate::handle_post_deploy_event(crate::HandlePostDeployEvent {
+        clients,
+        github_app_private_key: &config.github_app_private_key,
+        github_app_id: &config.github_app_id,
+        github_org: &repo.org,
+        github_repo: &repo.name,
+        github_ref_base: params.base,
+        github_ref_head: params.head,
+        github_slack_users: &config.github_slack_user_ids,
+        group_by_pull_request: config.group_by_pull_request,
+        notify_pull_request_mergers: config.notify_pull_request_mergers,
+        notify_pull_request_reviewers: config.notify_pull_request_reviewers,
+        comment_on_pull_requests: config.comment_on_pull_requests,
+        deploy_label: config
+            .heroku_app_deploy_labels
+            .get(params.app)
+            .map(String::as_str),
+        email_slack_users: &config.email_slack_user_ids,
+        slack_oauth_token: &config.slack_oauth_token,
+        heroku_release: params.release,
+        heroku_app_name: params.app,
+        kind: params.kind,
+        now: chrono::Utc::now().into(),
+        store,
+    })
+}
+
+struct RecordDeployment<'a> {
+    app: &'a str,
+    release: &'a str,
+    head: &'a str,
+    /// One of `in_progress`, `success` or `failure`.
+    state: &'a str,
+    log_url: &'a str,
+    github_org: Option<&'a str>,
+    github_repo: Option<&'a str>,
+}
+
+/// Record a release as a GitHub deployment to an environment named after the
+/// app, reusing the deployment of the commit if one exists.
+fn record_deployment(
+    params: RecordDeployment,
+    config: &Opt,
+    clients: &Clients,
+) -> Result<(), EveError> {
+    if !config.github_deployments {
+        return Ok(());
+    }
+    let repo = resolve_repo(
+        clients,
+        params.app,
+        params.github_org,
+        params.github_repo,
+        config,
+    )?;
+    let auth = github::RepoAuth {
+        private_key: &config.github_app_private_key,
+        app_id: &config.github_app_id,
+        org: &repo.org,
+        repo: &repo.name,
+    };
+    let description = format!("Heroku release {} of {}", params.release, params.app);
+    let deployment =
+        match github::list_deployments(&clients.github, &auth, params.head, params.app)?
+            .into_iter()
+            .next()
+        {
+            Some(deployment) => deployment,
+            None => github::create_deployment(
+                &clients.github,
+                &auth,
+                github::CreateDeployment {
+                    sha: params.head,
+                    environment: params.app,
+                    description: &description,
+                },
+            )?,
+        };
+    github::create_deployment_status(
+        &clients.github,
+        &auth,
+        github::CreateDeploymentStatus {
+            deployment_id: deployment.id,
+            state: params.state,
+            log_url: params.log_url,
+            environment_url: &format!("https://dashboard.heroku.com/apps/{}", params.app),
+            description: &description,
+        },
+    )?;
+    Ok(())
+}
+
+fn release_log_url(app: &str, release: &str) -> String {
+    format!(
+        "https://dashboard.heroku.com/apps/{}/activity/releases/{}",
+        app, release
+    )
+}
+
+/// Check that the source release of a promotion deployed `head`, since the
+/// source is parsed from the release description.
+fn is_promoted_from(
+    clients: &Clients,
+    source: &heroku::PromotionSource,
+    head: &str,
+    config: &Opt,
+) -> Result<bool, EveError> {
+    let heroku_token = &config.heroku_token;
+    let release =
+        match heroku::get_release(&clients.heroku, source.app, source.version, heroku_token) {
+            Ok(release) => release,
+            // the token may not have access to the source app.
+            Err(heroku::HerokuError::HttpError(ref e))
+                if e.status().map_or(false, |status| status.is_client_error()) =>
+            {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+    let slug = match release.slug {
+        Some(slug) => slug,
+        None => return Ok(false),
+    };
+    Ok(heroku::get_slug(&clients.heroku, source.app, &slug.id, heroku_token)?.commit == head)
+}
+
+/// Get the commit of the code release before `version` to compare `head`
+/// against. Returns `None` if the release didn't change code.
+fn release_base(
+    clients: &Clients,
+    app: &str,
+    version: i64,
+    head: &str,
+    config: &Opt,
+) -> Result<Option<String>, EveError> {
+    let heroku_token = &config.heroku_token;
+    let previous_slug =
+        match heroku::get_previous_release_slug(&clients.heroku, app, version, heroku_token)? {
+            Some(slug) => slug,
+            // the first code release of an app has nothing to compare against.
+            None => {
+                eprintln!(
+                    "no code release before v{} of {} to compare against",
+                    version, app
+                );
+                return Ok(None);
+            }
+        };
+    let base = heroku::get_slug(&clients.heroku, app, &previous_slug.id, heroku_token)?.commit;
+    // releases like config var changes reuse the previous slug.
+    if base == head {
+        return Ok(None);
+    }
+    Ok(Some(base))
+}
+
+pub fn run(job: &Job, config: &Opt, clients: &Clients, store: &Store) -> Result<(), EveError> {
+    match job {
+        Job::Release {
+            app,
+            version,
+            head,
+            rollback,
+            promoted_from,
+            promoted_from_version,
+            github_org,
+            github_repo,
+        } => {
+            let release = format!("v{}", version);
+            let source_app = match (promoted_from.as_deref(), promoted_from_version) {
+                (Some(source_app), Some(source_version)) => {
+                    let source = heroku::PromotionSource {
+                        app: source_app,
+                        version: *source_version,
+                    };
+                    if is_promoted_from(clients, &source, head, config)? {
+                        Some(source_app)
+                    } else {
+                        eprintln!(
+                            "{} {} doesn't match {} v{}, notifying as a release",
+                            app, release, source_app, source_version
+                        );
+                        None
+                    }
+                }
+                (source_app, _) => source_app,
+            };
+            // releases that didn't change code, like config var changes,
+            // have no commits to notify about.
+            if let Some(base) = release_base(clients, app, *version, head, config)? {
+                let kind = if *rollback {
+                    NotificationKind::Rollback
+                } else if let Some(source_app) = source_app {
+                    NotificationKind::Promotion { source_app }
+                } else {
+                    NotificationKind::Release
+                };
+                notify_deploy(
+                    NotifyDeploy {
+                        app,
+                        release: &release,
+                        kind,
+                        base: &base,
+                        head,
+                        github_org: github_org.as_deref(),
+                        github_repo: github_repo.as_deref(),
+                    },
+                    config,
+                    clients,
+                    store,
+                )?;
+            }
+            Ok(())
+        }
+        Job::ReleaseFailed {
+            app,
+            version,
+            head,
+            output_stream_url,
+            github_org,
+            github_repo,
+        } => {
+            let release = format!("v{}", version);
+            let output_stream_url = output_stream_url
+                .clone()
+                .unwrap_or_else(|| release_log_url(app, &release));
+            if let Some(base) = release_base(clients, app, *version, head, config)? {
+                notify_deploy(
+                    NotifyDeploy {
+                        app,
+                        release: &release,
+                        kind: NotificationKind::ReleaseFailed {
+                            output_stream_url: &output_stream_url,
+                        },
+                        base: &base,
+                        head,
+                        github_org: github_org.as_deref(),
+                        github_repo: github_repo.as_deref(),
+                    },
+                    config,
+                    clients,
+                    store,
+                )?;
+            }
+            Ok(())
+        }
+        Job::BuildFailed {
+            app,
+            build_id,
+            github_org,
+            github_repo,
+        } => {
+            let heroku_token = &config.heroku_token;
+            let build = heroku::get_build(&clients.heroku, app, build_id, heroku_token)?;
+            let head = match &build.source_blob.version {
+                Some(head) => head,
+                None => return Ok(()),
+            };
+            let base = match heroku::get_previous_successful_build_commit(
+                &clients.heroku,
+                app,
+                &build,
+                heroku_token,
+            )? {
+                Some(base) => base,
+                // nothing to compare against if no build has succeeded.
+                None => return Ok(()),
+            };
+            if &base == head {
+                return Ok(());
+            }
+            let output_stream_url = build
+                .output_stream_url
+                .clone()
+                .unwrap_or_else(|| format!("https://dashboard.heroku.com/apps/{}/activity", app));
+            notify_deploy(
+                NotifyDeploy {
+                    app,
+                    release: build_id,
+                    kind: NotificationKind::BuildFailed {
+                        output_stream_url: &output_stream_url,
+                    },
+                    base: &base,
+                    head,
+                    github_org: github_org.as_deref(),
+                    github_repo: github_repo.as_deref(),
+                },
+                config,
+                clients,
+                store,
+            )
+        }
+        Job::DynoCrashed {
+            app,
+            version,
+            dyno,
+            crashed_at,
+            github_org,
+            github_repo,
+        } => {
+            let heroku_token = &config.heroku_token;
+            let release = heroku::get_release(&clients.heroku, app, *version, heroku_token)?;
+            // only crashes soon after a release are blamed on it.
+            let window = chrono::Duration::minutes(config.dyno_crash_window_minutes);
+            if Utc.timestamp(*crashed_at, 0) - release.created_at > window {
+                return Ok(());
+            }
+            let slug = match release.slug {
+                Some(slug) => slug,
+                None => return Ok(()),
+            };
+            let head = heroku::get_slug(&clients.heroku, app, &slug.id, heroku_token)?.commit;
+            let base = match release_base(clients, app, *version, &head, config)? {
+                Some(base) => base,
+                None => return Ok(()),
+            };
+            let repo = resolve_repo(
+                clients,
+                app,
+                github_org.as_deref(),
+                github_repo.as_deref(),
+                config,
+            )?;
+            crate::handle_dyno_crash(crate::HandleDynoCrash {
+                clients,
+                github_app_private_key: &config.github_app_private_key,
+                github_app_id: &config.github_app_id,
+                github_org: &repo.org,
+                github_repo: &repo.name,
+                github_ref_base: &base,
+                github_ref_head: &head,
+                github_slack_users: &config.github_slack_user_ids,
+                email_slack_users: &config.email_slack_user_ids,
+                slack_oauth_token: &config.slack_oauth_token,
+                heroku_app_name: app,
+                heroku_release: &format!("v{}", version),
+                dyno_name: dyno,
+                store,
+            })
+        }
+        Job::Deploy {
+            app,
+            release,
+            base,
+            head,
+            github_org,
+            github_repo,
+        } => notify_deploy(
+            NotifyDeploy {
+                app,
+                release,
+                kind: NotificationKind::Release,
+                base,
+                head,
+                github_org: github_org.as_deref(),
+                github_repo: github_repo.as_deref(),
+            },
+            config,
+            clients,
+            store,
+        ),
+        Job::RecordDeployment {
+            app,
+            release,
+            head,
+            state,
+            output_stream_url,
+            github_org,
+            github_repo,
+        } => {
+            let log_url = output_stream_url
+                .clone()
+                .unwrap_or_else(|| release_log_url(app, release));
+            record_deployment(
+                RecordDeployment {
+                    app,
+                    release,
+                    head,
+                    state,
+                    log_url: &log_url,
+                    github_org: github_org.as_deref(),
+                    github_repo: github_repo.as_deref(),
+                },
+                config,
+                clients,
+            )
+        }
+    }
+}
@@ -3,16 +3,25 @@
 extern crate rocket;
 
 pub mod cli;
+pub mod client;
+pub mod commands;
 mod github;
 mod heroku;
 pub mod http;
+mod jobs;
+mod queue;
 mod slack;
+mod store;
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use serde_json::{json, Value};
 
 use std::collections::HashMap;
 
+/// Commits looked up to find their pull request per notification, so a
+/// large comparison doesn't use up the GitHub rate limit.
+const MAX_PULL_REQUEST_LOOKUPS: usize = 100;
+
 /// https://api.slack.com/reference/surfaces/formatting#escaping
 fn escape_mrkdwn(text: &str) -> String {
     text.replace("&", "&amp;")
@@ -20,32 +29,110 @@ fn escape_mrkdwn(text: &str) -> String {
         .replace(">", "&gt;")
 }
 
+/// What happened to the commits listed in a Slack message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationKind<'a> {
+    /// The commits were released to the app.
+    Release,
+    /// The commits were promoted to the app from another app in its pipeline.
+    Promotion { source_app: &'a str },
+    /// The commits were removed from the app by a rollback.
+    Rollback,
+    /// A build containing the commits failed.
+    BuildFailed { output_stream_url: &'a str },
+    /// The release phase command failed, so the commits were not released.
+    ReleaseFailed { output_stream_url: &'a str },
+}
+
+impl<'a> NotificationKind<'a> {
+    /// Plain text fallback for notifications.
+    const fn text(self) -> &'static str {
+        match self {
+            Self::Release => "Your changes have been deployed.",
+            Self::Promotion { .. } => "Your changes have been promoted.",
+            Self::Rollback => "Your changes have been rolled back.",
+            Self::BuildFailed { .. } => "A build of your changes failed.",
+            Self::ReleaseFailed { .. } => "A release of your changes failed.",
+        }
+    }
+}
+
 struct GetSlackMessage<'a> {
+    kind: NotificationKind<'a>,
     heroku_app_name: &'a str,
+    pull_requests: &'a Vec<PullRequest<'a>>,
     commits: &'a Vec<Commit<'a>>,
     release: &'a str,
     html_compare_url: &'a str,
+    /// Commits in the deploy that were left out because the comparison was
+    /// too large to fetch.
+    omitted_commits: i64,
     now: DateTime<FixedOffset>,
 }
 fn get_slack_message(params: GetSlackMessage) -> Value {
+    let pull_request_messages = params.pull_requests.iter().map(|pull_request| {
+        let relative_merge_time = &chrono_humanize::HumanTime::from(pull_request.merged_at - params.now).to_string();
+        let role = match pull_request.role {
+            PullRequestRole::Author => "",
+            PullRequestRole::CoAuthor => ", co-authored by you",
+            PullRequestRole::Merger => ", merged by you",
+            PullRequestRole::Reviewer => ", reviewed by you",
+        };
+        format!("<{pull_request_url}|{pull_request_title}> `#{number}`\nopened by {author_login}, merged {relative_merge_time}{role}",
+            pull_request_url=pull_request.url,
+            pull_request_title=escape_mrkdwn(pull_request.title),
+            number=pull_request.number,
+            author_login=escape_mrkdwn(pull_request.author_login),
+            relative_merge_time=relative_merge_time,
+            role=role
+        )
+    });
     let commit_messages = params.commits.iter().map(|commit| {
         let sha_short = &commit.sha[..7];
         let relative_commit_time = &chrono_humanize::HumanTime::from(commit.date - params.now).to_string();
-        format!("<{commit_url}|{commit_title}> `{head_short}`\n{commit_author_login} committed {relative_commit_time}",
+        let co_authored = if commit.co_authored { ", co-authored by you" } else { "" };
+        format!("<{commit_url}|{commit_title}> `{head_short}`\n{commit_author_login} committed {relative_commit_time}{co_authored}",
             commit_url=commit.url,
             commit_title=escape_mrkdwn(commit.title),
             head_short=sha_short,
-            commit_author_login=commit.author_login,
-            relative_commit_time=relative_commit_time
+            commit_author_login=escape_mrkdwn(commit.author_login),
+            relative_commit_time=relative_commit_time,
+            co_authored=co_authored
         )
 
-    }).collect::<Vec<String>>().join("\n");
+    });
+    let commit_messages = pull_request_messages
+        .chain(commit_messages)
+        .collect::<Vec<String>>()
+        .join("\n");
+    let commit_messages = if params.omitted_commits > 0 {
+        format!(
+            "{commit_messages}\n<{html_compare_url}|and {omitted_commits} more commits>",
+            commit_messages = commit_messages,
+            html_compare_url = params.html_compare_url,
+            omitted_commits = params.omitted_commits
+        )
+    } else {
+        commit_messages
+    };
+    let headline = match params.kind {
+        NotificationKind::Release => format!("Your changes have been released to <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> on Heroku.", heroku_app_name=params.heroku_app_name),
+        NotificationKind::Promotion { source_app } => format!("Your changes have been promoted from <https://dashboard.heroku.com/apps/{source_app}|`{source_app}`> to <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> on Heroku.", source_app=source_app, heroku_app_name=params.heroku_app_name),
+        NotificationKind::Rollback => format!("Your changes were rolled back from <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> in <https://dashboard.heroku.com/apps/{heroku_app_name}/activity/releases/{release}|{release}>.", heroku_app_name=params.heroku_app_name, release=params.release),
+        NotificationKind::BuildFailed { .. } => format!("A build of your changes failed on <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`>.", heroku_app_name=params.heroku_app_name),
+        NotificationKind::ReleaseFailed { .. } => format!("Your release to <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> failed during the release phase, so your changes were not released.", heroku_app_name=params.heroku_app_name),
+    };
+    let links = match params.kind {
+        NotificationKind::BuildFailed { output_stream_url } => format!("<{html_compare_url}|Compare diff> | <{output_stream_url}|Build log> | <https://dashboard.heroku.com/apps/{heroku_app_name}/activity|Build activity>", heroku_app_name=params.heroku_app_name, output_stream_url=output_stream_url, html_compare_url=params.html_compare_url),
+        NotificationKind::ReleaseFailed { output_stream_url } => format!("<{html_compare_url}|Compare diff> | <{output_stream_url}|Release phase output> | <https://dashboard.heroku.com/apps/{heroku_app_name}/activity/releases/{release}|Release log> | {release}", heroku_app_name=params.heroku_app_name, output_stream_url=output_stream_url, release=params.release, html_compare_url=params.html_compare_url),
+        _ => format!("<{html_compare_url}|Compare diff> | <https://dashboard.heroku.com/apps/{heroku_app_name}/activity/releases/{release}|Release log> | <https://dashboard.heroku.com/apps/{heroku_app_name}|Release activity> | {release}", heroku_app_name=params.heroku_app_name, release=params.release,html_compare_url=params.html_compare_url),
+    };
     json!([
         {
             "type": "section",
             "text": {
                 "type": "mrkdwn",
-                "text": format!("Your changes have been released to <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> on Heroku.",heroku_app_name=params.heroku_app_name)
+                "text": headline
             }
         },
         {
@@ -66,7 +153,33 @@ fn get_slack_message(params: GetSlackMessage) -> Value {
             "elements": [
                 {
                     "type": "mrkdwn",
-                    "text": format!("<{html_compare_url}|Compare diff> | <https://dashboard.heroku.com/apps/{heroku_app_name}/activity/releases/{release}|Release log> | <https://dashboard.heroku.com/apps/{heroku_app_name}|Release activity> | {release}", heroku_app_name=params.heroku_app_name, release=params.release,html_compare_url=params.html_compare_url)
+                    "text": links
+                }
+            ]
+        }
+    ])
+}
+
+struct GetUnhealthyReleaseMessage<'a> {
+    heroku_app_name: &'a str,
+    release: &'a str,
+    dyno_name: &'a str,
+}
+fn get_unhealthy_release_message(params: GetUnhealthyReleaseMessage) -> Value {
+    json!([
+        {
+            "type": "section",
+            "text": {
+                "type": "mrkdwn",
+                "text": format!("<https://dashboard.heroku.com/apps/{heroku_app_name}/activity/releases/{release}|{release}> of <https://dashboard.heroku.com/apps/{heroku_app_name}|`{heroku_app_name}`> may be unhealthy: `{dyno_name}` crashed.", heroku_app_name=params.heroku_app_name, release=params.release, dyno_name=escape_mrkdwn(params.dyno_name))
+            }
+        },
+        {
+            "type": "context",
+            "elements": [
+                {
+                    "type": "mrkdwn",
+                    "text": format!("<https://dashboard.heroku.com/apps/{heroku_app_name}/logs|Logs> | <https://dashboard.heroku.com/apps/{heroku_app_name}/metrics|Metrics> | {release}", heroku_app_name=params.heroku_app_name, release=params.release)
                 }
             ]
         }
@@ -78,6 +191,11 @@ pub enum EveError {
     SlackError(slack::SlackError),
     GitHubError(github::GitHubError),
     HerokuError(heroku::HerokuError),
+    StoreError(store::StoreError),
+    /// The request could not be authenticated.
+    Unauthorized(String),
+    /// The request body could not be read or parsed.
+    InvalidPayload(String),
     InternalError(String),
 }
 
@@ -98,22 +216,49 @@ impl std::convert::From<heroku::HerokuError> for EveError {
     }
 }
 
+impl std::convert::From<store::StoreError> for EveError {
+    fn from(e: store::StoreError) -> Self {
+        Self::StoreError(e)
+    }
+}
+
 pub type GithubUserId = i64;
 pub type SlackUserId = String;
 
 pub struct HandlePostDeployEvent<'a> {
+    pub clients: &'a client::Clients,
     pub github_app_private_key: &'a str,
     pub github_app_id: &'a str,
-    pub github_app_install_id: &'a str,
     pub github_org: &'a str,
     pub github_repo: &'a str,
     pub github_ref_base: &'a str,
     pub github_ref_head: &'a str,
     pub github_slack_users: &'a HashMap<GithubUserId, SlackUserId>,
+    /// List merged pull requests instead of their commits.
+    pub group_by_pull_request: bool,
+    /// Also notify whoever merged a pull request. Requires `group_by_pull_request`.
+    pub notify_pull_request_mergers: bool,
+    /// Also notify the approving reviewers of a pull request. Requires
+    /// `group_by_pull_request`.
+    pub notify_pull_request_reviewers: bool,
+    /// Keep a comment on each merged pull request listing the apps it was
+    /// deployed to.
+    pub comment_on_pull_requests: bool,
+    /// Label added to merged pull requests deployed to the app and removed
+    /// when they're rolled back.
+    pub deploy_label: Option<&'a str>,
+    /// Slack users keyed by lowercase git author email, for commits by emails
+    /// not linked to a GitHub account.
+    pub email_slack_users: &'a HashMap<String, SlackUserId>,
     pub slack_oauth_token: &'a str,
+    /// Release version, or the build id of failed builds.
     pub heroku_release: &'a str,
     pub heroku_app_name: &'a str,
+    /// What Heroku reported. Releases are reported as rollbacks when the head
+    /// commit is behind the base commit.
+    pub kind: NotificationKind<'a>,
     pub now: DateTime<FixedOffset>,
+    pub store: &'a store::Store,
 }
 #[derive(Debug)]
 struct Commit<'a> {
@@ -122,27 +267,210 @@ struct Commit<'a> {
     url: &'a str,
     sha: &'a str,
     date: DateTime<FixedOffset>,
+    /// The recipient is credited with a `Co-authored-by` trailer rather than
+    /// being the author.
+    co_authored: bool,
+}
+
+/// Why a pull request is listed in a recipient's message. Ordered by
+/// precedence for recipients with several roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PullRequestRole {
+    Author,
+    CoAuthor,
+    Merger,
+    Reviewer,
 }
+#[derive(Debug)]
+struct PullRequest<'a> {
+    number: i64,
+    title: &'a str,
+    url: &'a str,
+    author_login: &'a str,
+    merged_at: DateTime<FixedOffset>,
+    role: PullRequestRole,
+}
+
+/// Maps GitHub users and git emails to Slack users.
+#[derive(Clone, Copy)]
+struct SlackUsers<'a> {
+    github: &'a HashMap<GithubUserId, SlackUserId>,
+    /// Keyed by lowercase git author email.
+    email: &'a HashMap<String, SlackUserId>,
+}
+
+impl<'a> SlackUsers<'a> {
+    /// Find the Slack user for a git email, using the GitHub user of a GitHub
+    /// noreply email if the email isn't mapped to a Slack user.
+    fn by_email(self, email: &str) -> Option<&'a SlackUserId> {
+        self.email.get(&email.to_lowercase()).or_else(|| {
+            github::noreply_email_user_id(email).and_then(|github_id| self.github.get(&github_id))
+        })
+    }
+
+    /// Find the Slack user of a commit's author. Commits by emails that aren't
+    /// linked to a GitHub account have no author, so fall back to the email of
+    /// the git author.
+    fn commit_author(self, commit: &github::CommitNode) -> Option<&'a SlackUserId> {
+        commit
+            .author
+            .as_ref()
+            .and_then(|author| self.github.get(&author.id))
+            .or_else(|| self.by_email(&commit.commit.author.email))
+    }
+
+    /// Find the Slack users credited on a commit: its author, then the
+    /// co-authors from `Co-authored-by` trailers.
+    fn commit_credits(self, commit: &github::CommitNode) -> Vec<&'a SlackUserId> {
+        let mut slack_ids = self.commit_author(commit).into_iter().collect::<Vec<_>>();
+        for email in github::co_author_emails(&commit.commit.message) {
+            if let Some(slack_id) = self.by_email(email) {
+                if !slack_ids.contains(&slack_id) {
+                    slack_ids.push(slack_id);
+                }
+            }
+        }
+        slack_ids
+    }
+}
+
 pub fn handle_post_deploy_event(params: HandlePostDeployEvent) -> Result<(), EveError> {
-    // get the comments for the deploy.
-    let body = github::compare(github::Compare {
+    let auth = github::RepoAuth {
         private_key: params.github_app_private_key,
         app_id: params.github_app_id,
-        install_id: params.github_app_install_id,
         org: params.github_org,
         repo: params.github_repo,
-        base: params.github_ref_base,
-        head: params.github_ref_head,
-    })?;
+    };
+    let compare = |base: &str, head: &str| {
+        github::compare(&params.clients.github, github::Compare { auth, base, head })
+    };
+    // get the comments for the deploy.
+    let mut body = compare(params.github_ref_base, params.github_ref_head)?;
+
+    // a rollback deploys an older commit, so the commits from the base that
+    // aren't in the head are the ones that were removed.
+    let kind = match params.kind {
+        NotificationKind::Release | NotificationKind::Promotion { .. }
+            if body.status == "behind" =>
+        {
+            NotificationKind::Rollback
+        }
+        NotificationKind::Rollback if body.status == "behind" || body.status == "diverged" => {
+            NotificationKind::Rollback
+        }
+        NotificationKind::Rollback => NotificationKind::Release,
+        kind => kind,
+    };
+    if kind == NotificationKind::Rollback {
+        body = compare(params.github_ref_head, params.github_ref_base)?;
+    }
+
+    let omitted_commits = (body.total_commits - body.commits.len() as i64).max(0);
+
+    // find the merged pull request of each commit.
+    let mut pull_requests: Vec<github::PullRequest> = Vec::new();
+    let mut commit_pull_requests: HashMap<&str, usize> = HashMap::new();
+    if params.group_by_pull_request
+        || params.comment_on_pull_requests
+        || params.deploy_label.is_some()
+    {
+        // merge commits are looked up first, so the commits of their pull
+        // request don't need to be looked up one at a time.
+        let (merge_commits, other_commits): (Vec<_>, Vec<_>) = body
+            .commits
+            .iter()
+            .partition(|commit| commit.parents.len() > 1);
+        let mut lookups = 0;
+        for commit in merge_commits.into_iter().chain(other_commits) {
+            if commit_pull_requests.contains_key(commit.sha.as_str()) {
+                continue;
+            }
+            if lookups == MAX_PULL_REQUEST_LOOKUPS {
+                eprintln!(
+                    "stopped looking up pull requests for commits of {}/{} after {} lookups",
+                    params.github_org, params.github_repo, lookups
+                );
+                break;
+            }
+            lookups += 1;
+            let merged =
+                github::list_commit_pull_requests(&params.clients.github, &auth, &commit.sha)?
+                    .into_iter()
+                    .find(|pull_request| pull_request.merged_at.is_some());
+            if let Some(merged) = merged {
+                let number = merged.number;
+                let index = match pull_requests
+                    .iter()
+                    .position(|pull_request| pull_request.number == number)
+                {
+                    Some(index) => index,
+                    None => {
+                        pull_requests.push(merged);
+                        pull_requests.len() - 1
+                    }
+                };
+                commit_pull_requests.insert(&commit.sha, index);
+                if commit.parents.len() > 1 {
+                    let pull_request_commits =
+                        github::list_pull_request_commits(&params.clients.github, &auth, number)?;
+                    for pull_request_commit in pull_request_commits {
+                        if let Some(commit) = body
+                            .commits
+                            .iter()
+                            .find(|commit| commit.sha == pull_request_commit.sha)
+                        {
+                            commit_pull_requests.entry(&commit.sha).or_insert(index);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // pull requests are only listed in Slack messages when grouping by them.
+    let grouped_pull_requests: &[github::PullRequest] = if params.group_by_pull_request {
+        &pull_requests
+    } else {
+        &[]
+    };
+    let mut pull_request_recipients: Vec<HashMap<&SlackUserId, PullRequestRole>> =
+        grouped_pull_requests
+            .iter()
+            .map(|_| HashMap::new())
+            .collect();
+    let mut add_pull_request_recipient = |index: usize, slack_id, role| {
+        let current = pull_request_recipients[index]
+            .entry(slack_id)
+            .or_insert(role);
+        *current = (*current).min(role);
+    };
 
-    // aggregate the commit messages per user to insert into Slack message.
-    let mut github_id_to_message: HashMap<GithubUserId, Vec<Commit>> = HashMap::new();
+    // aggregate the commit messages per Slack user to insert into Slack message.
+    let slack_users = SlackUsers {
+        github: params.github_slack_users,
+        email: params.email_slack_users,
+    };
+    let mut slack_id_to_message: HashMap<&SlackUserId, Vec<Commit>> = HashMap::new();
     for commit in body.commits.iter() {
-        let author_id = commit.author.id;
+        let author_slack_id = slack_users.commit_author(commit);
+        let slack_ids = slack_users.commit_credits(commit);
 
-        let github_user_messages = github_id_to_message
-            .entry(author_id)
-            .or_insert_with(Vec::new);
+        // commits of a merged pull request are listed as the pull request.
+        if let Some(&index) = commit_pull_requests
+            .get(commit.sha.as_str())
+            .filter(|_| params.group_by_pull_request)
+        {
+            // the author of a merge commit is whoever merged the pull request,
+            // who is only notified with `notify_pull_request_mergers`.
+            if commit.parents.len() <= 1 {
+                for slack_id in slack_ids {
+                    add_pull_request_recipient(index, slack_id, PullRequestRole::CoAuthor);
+                }
+            }
+            continue;
+        }
+        if slack_ids.is_empty() {
+            continue;
+        }
 
         // select the "title" of the commit be slicing off the string at first
         // new line character.
@@ -160,28 +488,302 @@ pub fn handle_post_deploy_event(params: HandlePostDeployEvent) -> Result<(), Eve
                     commit.commit.author.date
                 ))
             })?;
-        github_user_messages.push(Commit {
-            author_login: &commit.author.login,
-            title: commit_title,
-            url: &commit.html_url,
-            sha: &commit.sha,
-            date: commit_date,
+        for slack_id in slack_ids {
+            slack_id_to_message
+                .entry(slack_id)
+                .or_insert_with(Vec::new)
+                .push(Commit {
+                    author_login: commit
+                        .author
+                        .as_ref()
+                        .map_or(&commit.commit.author.name, |author| &author.login),
+                    title: commit_title,
+                    url: &commit.html_url,
+                    sha: &commit.sha,
+                    date: commit_date,
+                    co_authored: Some(slack_id) != author_slack_id,
+                });
+        }
+    }
+
+    // aggregate the pull requests per Slack user.
+    for (index, pull_request) in grouped_pull_requests.iter().enumerate() {
+        if let Some(slack_id) = params.github_slack_users.get(&pull_request.user.id) {
+            add_pull_request_recipient(index, slack_id, PullRequestRole::Author);
+        }
+        if params.notify_pull_request_mergers {
+            let detail =
+                github::get_pull_request(&params.clients.github, &auth, pull_request.number)?;
+            if let Some(slack_id) = detail
+                .merged_by
+                .and_then(|merger| params.github_slack_users.get(&merger.id))
+            {
+                add_pull_request_recipient(index, slack_id, PullRequestRole::Merger);
+            }
+        }
+        if params.notify_pull_request_reviewers {
+            let reviews = github::list_pull_request_reviews(
+                &params.clients.github,
+                &auth,
+                pull_request.number,
+            )?;
+            for review in reviews.iter().filter(|review| review.state == "APPROVED") {
+                if let Some(slack_id) = review
+                    .user
+                    .as_ref()
+                    .and_then(|reviewer| params.github_slack_users.get(&reviewer.id))
+                {
+                    add_pull_request_recipient(index, slack_id, PullRequestRole::Reviewer);
+                }
+            }
+        }
+    }
+    let mut slack_id_to_pull_requests: HashMap<&SlackUserId, Vec<PullRequest>> = HashMap::new();
+    for (pull_request, recipients) in grouped_pull_requests
+        .iter()
+        .zip(pull_request_recipients.iter())
+    {
+        // pull requests from `list_commit_pull_requests` are merged.
+        let merged_at = pull_request.merged_at.as_deref().unwrap_or_default();
+        let merged_at = DateTime::parse_from_rfc3339(merged_at).map_err(|_| {
+            EveError::InternalError(format!(
+                "Could not parse merge date of pull request. {}",
+                merged_at
+            ))
+        })?;
+        for (slack_id, role) in recipients.iter() {
+            slack_id_to_pull_requests
+                .entry(slack_id)
+                .or_insert_with(Vec::new)
+                .push(PullRequest {
+                    number: pull_request.number,
+                    title: &pull_request.title,
+                    url: &pull_request.html_url,
+                    author_login: &pull_request.user.login,
+                    merged_at,
+                    role: *role,
+                });
+        }
+    }
+
+    // send messages to each Slack user with GitHub commits or pull requests.
+    let topic = match kind {
+        NotificationKind::BuildFailed { .. } => store::Topic::Build,
+        _ => store::Topic::Release,
+    };
+    let no_pull_requests = Vec::new();
+    let no_commits = Vec::new();
+    let mut slack_ids = slack_id_to_message
+        .keys()
+        .chain(slack_id_to_pull_requests.keys())
+        .collect::<Vec<_>>();
+    slack_ids.sort();
+    slack_ids.dedup();
+    for slack_id in slack_ids {
+        // skip users notified by a previous attempt of this deploy.
+        if params.store.is_notified(
+            topic,
+            params.heroku_app_name,
+            params.heroku_release,
+            slack_id,
+        )? {
+            continue;
+        }
+        let slack_msg = get_slack_message(GetSlackMessage {
+            kind,
+            heroku_app_name: params.heroku_app_name,
+            pull_requests: slack_id_to_pull_requests
+                .get(slack_id)
+                .unwrap_or(&no_pull_requests),
+            commits: slack_id_to_message.get(slack_id).unwrap_or(&no_commits),
+            html_compare_url: &body.html_url,
+            omitted_commits,
+            release: params.heroku_release,
+            now: params.now,
         });
+        slack::chat_post_message(
+            &params.clients.slack,
+            params.slack_oauth_token,
+            slack_id,
+            kind.text(),
+            slack_msg,
+        )?;
+        params.store.record_notification(
+            topic,
+            params.heroku_app_name,
+            params.heroku_release,
+            slack_id,
+        )?;
+    }
+
+    // failed builds and releases didn't reach the app, so only releases and
+    // rollbacks are commented on and labeled.
+    let rolled_back = match kind {
+        NotificationKind::Release | NotificationKind::Promotion { .. } => Some(false),
+        NotificationKind::Rollback => Some(true),
+        _ => None,
+    };
+    if let (true, Some(rolled_back)) = (params.comment_on_pull_requests, rolled_back) {
+        for pull_request in pull_requests.iter() {
+            comment_on_pull_request(&params, auth, pull_request.number, rolled_back)?;
+        }
+    }
+    if let (Some(label), Some(rolled_back)) = (params.deploy_label, rolled_back) {
+        for pull_request in pull_requests.iter() {
+            if rolled_back {
+                github::remove_label(&params.clients.github, &auth, pull_request.number, label)?;
+            } else {
+                github::add_label(&params.clients.github, &auth, pull_request.number, label)?;
+            }
+        }
     }
+    Ok(())
+}
 
-    // send messages to each Slack user with GitHub commits.
-    for (github_id, commits) in github_id_to_message.iter() {
-        let slack_id = params.github_slack_users.get(github_id);
-        if let Some(slack_id) = slack_id {
-            let slack_msg = get_slack_message(GetSlackMessage {
-                heroku_app_name: params.heroku_app_name,
-                commits,
-                html_compare_url: &body.html_url,
-                release: params.heroku_release,
-                now: params.now,
-            });
-            slack::chat_post_message(params.slack_oauth_token, slack_id, slack_msg)?;
+fn get_pull_request_comment(deploys: &[store::PullRequestDeploy]) -> String {
+    deploys
+        .iter()
+        .map(|deploy| {
+            let deployed_at = Utc
+                .timestamp(deploy.deployed_at, 0)
+                .format("%Y-%m-%d %H:%M UTC");
+            if deploy.rolled_back {
+                format!(
+                    "Rolled back from `{}` in {} at {}",
+                    deploy.app, deploy.release, deployed_at
+                )
+            } else {
+                format!(
+                    "Deployed to `{}` in {} at {}",
+                    deploy.app, deploy.release, deployed_at
+                )
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Record the release of a pull request and update the pull request's
+/// comment, creating it on the first release or if it was deleted.
+fn comment_on_pull_request(
+    params: &HandlePostDeployEvent,
+    auth: github::RepoAuth,
+    number: i64,
+    rolled_back: bool,
+) -> Result<(), EveError> {
+    let (org, repo) = (params.github_org, params.github_repo);
+    params.store.record_pull_request_deploy(
+        org,
+        repo,
+        number,
+        &store::PullRequestDeploy {
+            app: params.heroku_app_name.to_string(),
+            release: params.heroku_release.to_string(),
+            rolled_back,
+            deployed_at: params.now.timestamp(),
+        },
+    )?;
+    let comment = get_pull_request_comment(&params.store.pull_request_deploys(org, repo, number)?);
+    let client = &params.clients.github;
+    if let Some(comment_id) = params.store.pull_request_comment_id(org, repo, number)? {
+        match github::update_issue_comment(client, &auth, comment_id, &comment) {
+            Ok(_) => return Ok(()),
+            // the comment was deleted, so create a new one.
+            Err(github::GitHubError::HttpError(ref e))
+                if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // another job deploying the pull request may be creating the comment, so
+    // retry once it's recorded and update it instead.
+    if !params.store.claim_pull_request_comment(org, repo, number)? {
+        return Err(EveError::InternalError(format!(
+            "comment on {}/{}#{} is being created by another job",
+            org, repo, number
+        )));
+    }
+    let created = github::create_issue_comment(client, &auth, number, &comment)?;
+    params
+        .store
+        .record_pull_request_comment(org, repo, number, created.id)?;
+    Ok(())
+}
+
+pub struct HandleDynoCrash<'a> {
+    pub clients: &'a client::Clients,
+    pub github_app_private_key: &'a str,
+    pub github_app_id: &'a str,
+    pub github_org: &'a str,
+    pub github_repo: &'a str,
+    /// Commits of the releases before and of the crashed release.
+    pub github_ref_base: &'a str,
+    pub github_ref_head: &'a str,
+    pub github_slack_users: &'a HashMap<GithubUserId, SlackUserId>,
+    pub email_slack_users: &'a HashMap<String, SlackUserId>,
+    pub slack_oauth_token: &'a str,
+    pub heroku_app_name: &'a str,
+    pub heroku_release: &'a str,
+    pub dyno_name: &'a str,
+    pub store: &'a store::Store,
+}
+/// Warn the authors of the commits in a release when one of its dynos
+/// crashes. Each author is warned once per release.
+pub fn handle_dyno_crash(params: HandleDynoCrash) -> Result<(), EveError> {
+    let auth = github::RepoAuth {
+        private_key: params.github_app_private_key,
+        app_id: params.github_app_id,
+        org: params.github_org,
+        repo: params.github_repo,
+    };
+    let body = github::compare(
+        &params.clients.github,
+        github::Compare {
+            auth,
+            base: params.github_ref_base,
+            head: params.github_ref_head,
+        },
+    )?;
+    let slack_users = SlackUsers {
+        github: params.github_slack_users,
+        email: params.email_slack_users,
+    };
+    let mut slack_ids = body
+        .commits
+        .iter()
+        .flat_map(|commit| slack_users.commit_credits(commit))
+        .collect::<Vec<_>>();
+    slack_ids.sort();
+    slack_ids.dedup();
+    for slack_id in slack_ids {
+        if params.store.is_notified(
+            store::Topic::DynoCrash,
+            params.heroku_app_name,
+            params.heroku_release,
+            slack_id,
+        )? {
+            continue;
         }
+        let slack_msg = get_unhealthy_release_message(GetUnhealthyReleaseMessage {
+            heroku_app_name: params.heroku_app_name,
+            release: params.heroku_release,
+            dyno_name: params.dyno_name,
+        });
+        slack::chat_post_message(
+            &params.clients.slack,
+            params.slack_oauth_token,
+            slack_id,
+            &format!(
+                "{} of {} may be unhealthy.",
+                params.heroku_release, params.heroku_app_name
+            ),
+            slack_msg,
+        )?;
+        params.store.record_notification(
+            store::Topic::DynoCrash,
+            params.heroku_app_name,
+            params.heroku_release,
+            slack_id,
+        )?;
     }
     Ok(())
 }
@@ -190,21 +792,205 @@ pub fn handle_post_deploy_event(params: HandlePostDeployEvent) -> Result<(), Eve
 mod test {
     use super::*;
 
+    const COMMIT_URL: &str =
+        "https://github.com/acme-corp/blog/commit/56b515000c090c0ba5f285c6e19f9451788413f1";
+    const HTML_COMPARE_URL: &str = "https://github.com/acme-corp/blog/compare/7c68a71a87d12cc2404aed192840674af84f3df4...56b515000c090c0ba5f285c6e19f9451788413f1";
+
+    /// When the commits in the tests were authored.
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2020-06-01T09:12:00-04:00").unwrap()
+    }
+
+    /// A commit authored at `date()`, with the SHA at the end of its URL.
+    fn commit<'a>(url: &'a str, title: &'a str, author_login: &'a str) -> Commit<'a> {
+        Commit {
+            author_login,
+            title,
+            url,
+            sha: &url[url.rfind('/').unwrap() + 1..],
+            date: date(),
+            co_authored: false,
+        }
+    }
+
+    #[test]
+    fn test_pull_request_comment() {
+        let deploy =
+            |app: &str, release: &str, rolled_back, deployed_at| store::PullRequestDeploy {
+                app: app.to_string(),
+                release: release.to_string(),
+                rolled_back,
+                deployed_at,
+            };
+        assert_eq!(
+            get_pull_request_comment(&[
+                deploy("acme-staging", "v122", false, 1591016520),
+                deploy("acme-prod", "v123", true, 1591020120),
+            ]),
+            "Deployed to `acme-staging` in v122 at 2020-06-01 13:02 UTC\nRolled back from `acme-prod` in v123 at 2020-06-01 14:02 UTC"
+        );
+    }
+
     #[test]
     fn test_escaping_slack_messages() {
         let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Release,
             heroku_app_name: "",
+            pull_requests: &vec![],
             commits: &vec![Commit {
                 author_login: "ghost",
                 title: "Fix <Foo/> & some other thing",
                 url: "https://example.org",
                 sha: "56b515000c090c0ba5f285c6e19f9451788413f1",
                 date: DateTime::parse_from_rfc3339("2015-12-19T16:39:57-08:00").unwrap(),
+                co_authored: false,
             }],
             release: "heroku-release-id",
             html_compare_url: "https://github.com/repos/ghost/repo/compare/7c68a71a87d12cc2404aed192840674af84f3df4...master",
+            omitted_commits: 0,
             now: chrono::Utc::now().into()
         });
         insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
     }
+
+    #[test]
+    fn test_rollback_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Rollback,
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![],
+            commits: &vec![commit(COMMIT_URL, "Add search to blog", "ghost")],
+            release: "v42",
+            html_compare_url: "https://github.com/acme-corp/blog/compare/56b515000c090c0ba5f285c6e19f9451788413f1...7c68a71a87d12cc2404aed192840674af84f3df4",
+            omitted_commits: 0,
+            now: date() + chrono::Duration::hours(3),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_promotion_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Promotion {
+                source_app: "acme-staging",
+            },
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![],
+            commits: &vec![commit(COMMIT_URL, "Add search to blog", "ghost")],
+            release: "v43",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 0,
+            now: date() + chrono::Duration::days(1),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_build_failed_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::BuildFailed {
+                output_stream_url: "https://build-output.heroku.com/streams/01/01234567-89ab-cdef-0123-456789abcdef/logs/0123456789abcdef.log",
+            },
+            heroku_app_name: "acme-staging",
+            pull_requests: &vec![],
+            commits: &vec![commit(COMMIT_URL, "Add search to blog", "ghost")],
+            release: "01234567-89ab-cdef-0123-456789abcdef",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 0,
+            now: date() + chrono::Duration::minutes(5),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_release_failed_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::ReleaseFailed {
+                output_stream_url: "https://release-output.heroku.com/streams/01/01234567-89ab-cdef-0123-456789abcdef/logs/0123456789abcdef.log",
+            },
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![],
+            commits: &vec![commit(COMMIT_URL, "Add search to blog", "ghost")],
+            release: "v44",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 0,
+            now: date() + chrono::Duration::minutes(5),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_unhealthy_release_slack_message() {
+        let res = get_unhealthy_release_message(GetUnhealthyReleaseMessage {
+            heroku_app_name: "acme-prod",
+            release: "v42",
+            dyno_name: "web.1",
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Release,
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![],
+            commits: &vec![commit(COMMIT_URL, "Add search to blog", "ghost")],
+            release: "v45",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 312,
+            now: date() + chrono::Duration::hours(2),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_co_authored_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Release,
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![],
+            commits: &vec![Commit {
+                co_authored: true,
+                ..commit(COMMIT_URL, "Add search to blog", "ghost")
+            }],
+            release: "v46",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 0,
+            now: date() + chrono::Duration::hours(1),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
+
+    #[test]
+    fn test_pull_request_slack_message() {
+        let res = get_slack_message(GetSlackMessage {
+            kind: NotificationKind::Release,
+            heroku_app_name: "acme-prod",
+            pull_requests: &vec![
+                PullRequest {
+                    number: 12,
+                    title: "Add search to blog",
+                    url: "https://github.com/acme-corp/blog/pull/12",
+                    author_login: "ghost",
+                    merged_at: date(),
+                    role: PullRequestRole::Author,
+                },
+                PullRequest {
+                    number: 15,
+                    title: "Fix <Foo/> & some other thing",
+                    url: "https://github.com/acme-corp/blog/pull/15",
+                    author_login: "chdsbd",
+                    merged_at: date() + chrono::Duration::minutes(30),
+                    role: PullRequestRole::Reviewer,
+                },
+            ],
+            commits: &vec![commit(COMMIT_URL, "Fix typo in README", "ghost")],
+            release: "v47",
+            html_compare_url: HTML_COMPARE_URL,
+            omitted_commits: 0,
+            now: date() + chrono::Duration::hours(2),
+        });
+        insta::assert_display_snapshot!(serde_json::to_string_pretty(&res).unwrap());
+    }
 }
@@ -1,3 +1,11 @@
 fn main() {
-    eve::http::start_server(eve::cli::parse_args());
+    match eve::cli::parse_args() {
+        eve::cli::Args::Command(cmd) => {
+            if let Err(e) = eve::commands::run(&cmd) {
+                eprintln!("error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        eve::cli::Args::Server(opt) => eve::http::start_server(opt),
+    }
 }
@@ -0,0 +1,449 @@
+use rusqlite::{params, OptionalExtension};
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cli::Opt;
+use crate::client::Clients;
+use crate::jobs::{self, Job};
+use crate::store::{now, Store, StoreError};
+
+/// How long an idle worker waits before checking for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a claimed job is hidden from other workers. If a worker dies
+/// mid-job, the job becomes available again after this many seconds.
+const LEASE_SECONDS: i64 = 15 * 60;
+/// Delay before the first retry. Doubles for every failed attempt.
+const BACKOFF_BASE_SECONDS: i64 = 30;
+const BACKOFF_MAX_SECONDS: i64 = 60 * 60;
+
+/// A job claimed by a worker.
+struct ClaimedJob {
+    id: i64,
+    attempts: i64,
+    payload: String,
+}
+
+/// Delay before the next attempt of a job that has failed `attempts` times.
+fn backoff_seconds(attempts: i64) -> i64 {
+    let exponent = (attempts - 1).max(0).min(16) as u32;
+    (BACKOFF_BASE_SECONDS * 2_i64.pow(exponent)).min(BACKOFF_MAX_SECONDS)
+}
+
+/// What a webhook delivery is about. Each is only queued once.
+#[derive(Clone, Copy)]
+pub enum Subject<'a> {
+    /// A release version, like `v42`.
+    Release(&'a str),
+    /// A release version that is still running its release phase. The
+    /// release can be queued again once it finishes.
+    PendingRelease(&'a str),
+    /// A build id.
+    Build(&'a str),
+}
+
+/// Identifies a webhook delivery so repeated deliveries can be dropped.
+pub struct Delivery<'a> {
+    /// Heroku webhook event ID, which stays the same when Heroku retries.
+    pub event_id: Option<&'a str>,
+    pub app: &'a str,
+    /// `None` for events that are only identified by their event ID.
+    pub subject: Option<Subject<'a>>,
+}
+
+/// Job queue persisted to SQLite so queued notifications survive restarts.
+///
+/// Jobs that fail are retried with exponential backoff. Jobs that run out of
+/// attempts are kept in the table with `dead = 1` for inspection.
+#[derive(Clone)]
+pub struct Queue {
+    store: Store,
+}
+
+impl Queue {
+    pub const fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Enqueue `jobs` unless the webhook event or its subject was already
+    /// seen.
+    ///
+    /// Returns `false` for duplicate deliveries.
+    pub fn enqueue_once(&self, jobs: &[Job], delivery: &Delivery) -> Result<bool, StoreError> {
+        let now = now();
+        let mut conn = self.store.conn();
+        let tx = conn.transaction()?;
+        let new_event = match delivery.event_id {
+            Some(event_id) => {
+                tx.execute(
+                    "INSERT OR IGNORE INTO webhook_events (id, created_at) VALUES (?1, ?2)",
+                    params![event_id, now],
+                )? == 1
+            }
+            None => true,
+        };
+        let new_subject =
+            match delivery.subject {
+                Some(Subject::Release(release)) => {
+                    tx.execute(
+                        "INSERT INTO releases (app, release, pending, created_at)
+                     VALUES (?1, ?2, 0, ?3)
+                     ON CONFLICT (app, release) DO UPDATE SET pending = 0 WHERE pending = 1",
+                        params![delivery.app, release, now],
+                    )? == 1
+                }
+                // a release that already finished isn't pending anymore.
+                Some(Subject::PendingRelease(release)) => {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO releases (app, release, pending, created_at)
+                     VALUES (?1, ?2, 1, ?3)",
+                        params![delivery.app, release, now],
+                    )? == 1
+                }
+                Some(Subject::Build(build_id)) => tx.execute(
+                    "INSERT OR IGNORE INTO builds (app, build_id, created_at) VALUES (?1, ?2, ?3)",
+                    params![delivery.app, build_id, now],
+                )? == 1,
+                None => true,
+            };
+        let is_new = new_event && new_subject;
+        if is_new {
+            for job in jobs {
+                tx.execute(
+                    "INSERT INTO jobs (payload, ordering_key, run_at, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![serde_json::to_string(job)?, job.ordering_key(), now, now],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(is_new)
+    }
+
+    /// Lease the next runnable job so no other worker picks it up. Jobs wait
+    /// for earlier jobs with the same ordering key to finish or die.
+    fn claim(&self) -> Result<Option<ClaimedJob>, StoreError> {
+        let now = now();
+        let conn = self.store.conn();
+        let job = conn
+            .query_row(
+                "SELECT id, attempts, payload FROM jobs AS job
+                 WHERE dead = 0 AND run_at <= ?1
+                   AND (locked_until IS NULL OR locked_until <= ?1)
+                   AND NOT EXISTS (
+                     SELECT 1 FROM jobs AS earlier
+                     WHERE earlier.ordering_key = job.ordering_key
+                       AND earlier.id < job.id AND earlier.dead = 0
+                   )
+                 ORDER BY run_at, id
+                 LIMIT 1",
+                params![now],
+                |row| {
+                    Ok(ClaimedJob {
+                        id: row.get(0)?,
+                        attempts: row.get(1)?,
+                        payload: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        if let Some(job) = &job {
+            conn.execute(
+                "UPDATE jobs SET locked_until = ?1 WHERE id = ?2",
+                params![now + LEASE_SECONDS, job.id],
+            )?;
+        }
+        Ok(job)
+    }
+
+    fn complete(&self, id: i64) -> Result<(), StoreError> {
+        self.store
+            .conn()
+            .execute("DELETE FROM jobs WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Schedule a retry for a failed job, or dead-letter it once it has used
+    /// all of its attempts.
+    fn fail(&self, job: &ClaimedJob, error: &str, max_attempts: i64) -> Result<(), StoreError> {
+        let attempts = job.attempts + 1;
+        let dead = attempts >= max_attempts;
+        self.store.conn().execute(
+            "UPDATE jobs
+             SET attempts = ?1, run_at = ?2, locked_until = NULL, last_error = ?3, dead = ?4
+             WHERE id = ?5",
+            params![
+                attempts,
+                now() + backoff_seconds(attempts),
+                error,
+                dead,
+                job.id
+            ],
+        )?;
+        if dead {
+            eprintln!("job {} dead-lettered after {} attempts", job.id, attempts);
+        }
+        Ok(())
+    }
+}
+
+fn run_next(queue: &Queue, config: &Opt, clients: &Clients) -> Result<bool, StoreError> {
+    let job = match queue.claim()? {
+        Some(job) => job,
+        None => return Ok(false),
+    };
+    let payload = match serde_json::from_str::<Job>(&job.payload) {
+        Ok(payload) => payload,
+        // a payload we can't read will never succeed, so don't retry it.
+        Err(e) => {
+            queue.fail(&job, &e.to_string(), 0)?;
+            return Ok(true);
+        }
+    };
+    match jobs::run(&payload, config, clients, queue.store()) {
+        Ok(()) => queue.complete(job.id)?,
+        Err(e) => {
+            let error = format!("{:?}", e);
+            eprintln!("job {} failed: {}", job.id, error);
+            queue.fail(&job, &error, config.max_job_attempts)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Spawn worker threads that process jobs until the process exits.
+pub fn start_workers(queue: &Queue, config: &Arc<Opt>, clients: &Clients) {
+    for _ in 0..config.worker_count {
+        let queue = queue.clone();
+        let config = Arc::clone(config);
+        let clients = clients.clone();
+        thread::spawn(move || loop {
+            match run_next(&queue, &config, &clients) {
+                Ok(true) => {}
+                Ok(false) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    eprintln!("problem processing job queue: {:?}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::NO_PARAMS;
+
+    fn pending_jobs(queue: &Queue) -> i64 {
+        queue
+            .store
+            .conn()
+            .query_row(
+                "SELECT COUNT(*) FROM jobs WHERE dead = 0",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn deploy_job() -> Job {
+        Job::Deploy {
+            app: "acme-prod".to_string(),
+            release: "v2".to_string(),
+            base: "a".to_string(),
+            head: "b".to_string(),
+            github_org: Some("acme-corp".to_string()),
+            github_repo: Some("blog".to_string()),
+        }
+    }
+
+    fn deployment_job(state: &str) -> Job {
+        Job::RecordDeployment {
+            app: "acme-prod".to_string(),
+            release: "v2".to_string(),
+            head: "b".to_string(),
+            state: state.to_string(),
+            output_stream_url: None,
+            github_org: Some("acme-corp".to_string()),
+            github_repo: Some("blog".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff_seconds(1), 30);
+        assert_eq!(backoff_seconds(2), 60);
+        assert_eq!(backoff_seconds(3), 120);
+        assert_eq!(backoff_seconds(100), BACKOFF_MAX_SECONDS);
+    }
+
+    #[test]
+    fn test_retry_and_dead_letter() {
+        let queue = Queue::new(Store::open(":memory:").unwrap());
+        queue
+            .enqueue_once(
+                &[deploy_job()],
+                &Delivery {
+                    event_id: None,
+                    app: "acme-prod",
+                    subject: Some(Subject::Release("v2")),
+                },
+            )
+            .unwrap();
+        let job = queue.claim().unwrap().expect("job should be runnable");
+        assert!(queue.claim().unwrap().is_none(), "claimed job is leased");
+
+        queue.fail(&job, "boom", 2).unwrap();
+        assert_eq!(pending_jobs(&queue), 1);
+        assert!(queue.claim().unwrap().is_none(), "retry is delayed");
+
+        let job = ClaimedJob { attempts: 1, ..job };
+        queue.fail(&job, "boom", 2).unwrap();
+        assert_eq!(pending_jobs(&queue), 0, "job is dead-lettered");
+    }
+
+    #[test]
+    fn test_enqueue_once() {
+        let queue = Queue::new(Store::open(":memory:").unwrap());
+        let delivery = Delivery {
+            event_id: Some("01234567-89ab-cdef-0123-456789abcdef"),
+            app: "acme-prod",
+            subject: Some(Subject::Release("v2")),
+        };
+        assert!(queue.enqueue_once(&[deploy_job()], &delivery).unwrap());
+        assert!(
+            !queue.enqueue_once(&[deploy_job()], &delivery).unwrap(),
+            "retried delivery is dropped"
+        );
+        assert!(
+            !queue
+                .enqueue_once(
+                    &[deploy_job()],
+                    &Delivery {
+                        event_id: Some("another-event"),
+                        ..delivery
+                    }
+                )
+                .unwrap(),
+            "release was already queued by another event"
+        );
+        assert!(
+            queue
+                .enqueue_once(
+                    &[deploy_job()],
+                    &Delivery {
+                        event_id: Some("build-event"),
+                        app: "acme-prod",
+                        subject: Some(Subject::Build("v2")),
+                    }
+                )
+                .unwrap(),
+            "builds don't share keys with releases"
+        );
+        assert_eq!(pending_jobs(&queue), 2);
+    }
+
+    #[test]
+    fn test_enqueue_pending_release() {
+        let queue = Queue::new(Store::open(":memory:").unwrap());
+        let delivery = |event_id, subject| Delivery {
+            event_id: Some(event_id),
+            app: "acme-prod",
+            subject: Some(subject),
+        };
+        assert!(queue
+            .enqueue_once(
+                &[deploy_job()],
+                &delivery("v2-pending", Subject::PendingRelease("v2"))
+            )
+            .unwrap());
+        assert!(
+            queue
+                .enqueue_once(
+                    &[deploy_job()],
+                    &delivery("v2-succeeded", Subject::Release("v2"))
+                )
+                .unwrap(),
+            "pending release is queued again once it finishes"
+        );
+        assert!(
+            !queue
+                .enqueue_once(
+                    &[deploy_job()],
+                    &delivery("v2-retried", Subject::Release("v2"))
+                )
+                .unwrap(),
+            "finished release is only queued once"
+        );
+        assert!(queue
+            .enqueue_once(
+                &[deploy_job()],
+                &delivery("v3-succeeded", Subject::Release("v3"))
+            )
+            .unwrap());
+        assert!(
+            !queue
+                .enqueue_once(
+                    &[deploy_job()],
+                    &delivery("v3-pending", Subject::PendingRelease("v3"))
+                )
+                .unwrap(),
+            "late pending delivery of a finished release is dropped"
+        );
+        assert_eq!(pending_jobs(&queue), 3);
+    }
+
+    #[test]
+    fn test_record_deployments_in_order() {
+        let queue = Queue::new(Store::open(":memory:").unwrap());
+        let enqueue = |event_id, subject, jobs: &[Job]| {
+            queue
+                .enqueue_once(
+                    jobs,
+                    &Delivery {
+                        event_id: Some(event_id),
+                        app: "acme-prod",
+                        subject: Some(subject),
+                    },
+                )
+                .unwrap()
+        };
+        enqueue(
+            "v2-pending",
+            Subject::PendingRelease("v2"),
+            &[deployment_job("in_progress")],
+        );
+        enqueue(
+            "v2-succeeded",
+            Subject::Release("v2"),
+            &[deploy_job(), deployment_job("success")],
+        );
+
+        let started = queue.claim().unwrap().expect("job should be runnable");
+        queue.fail(&started, "boom", 2).unwrap();
+        let notification = queue.claim().unwrap().expect("job should be runnable");
+        assert_eq!(
+            notification.id,
+            started.id + 1,
+            "failing deployment status doesn't block the notification"
+        );
+        assert!(
+            queue.claim().unwrap().is_none(),
+            "deployment status waits for the earlier status"
+        );
+
+        let started = ClaimedJob {
+            attempts: 1,
+            ..started
+        };
+        queue.fail(&started, "boom", 2).unwrap();
+        let finished = queue.claim().unwrap().expect("job should be runnable");
+        assert_eq!(finished.id, started.id + 2);
+    }
+}
@@ -1,5 +1,7 @@
 use serde_json::{json, Value};
 
+use crate::client::ApiClient;
+
 #[derive(Debug)]
 pub enum SlackError {
     HttpError(reqwest::Error),
@@ -12,17 +14,23 @@ impl std::convert::From<reqwest::Error> for SlackError {
 }
 
 /// https://slack.com/api/chat.postMessage
-pub fn chat_post_message(token: &str, channel: &str, blocks: Value) -> Result<(), SlackError> {
-    let client = reqwest::blocking::Client::new();
-    let res = client
-        .post("https://slack.com/api/chat.postMessage")
-        .bearer_auth(token)
-        .json(&json!({
-            "channel": channel,
-            "text": "Your changes have been deployed.",
-            "blocks": blocks
-        }))
-        .send()?;
+pub fn chat_post_message(
+    client: &ApiClient,
+    token: &str,
+    channel: &str,
+    text: &str,
+    blocks: Value,
+) -> Result<(), SlackError> {
+    let res = client.send(
+        client
+            .post("/chat.postMessage")
+            .bearer_auth(token)
+            .json(&json!({
+                "channel": channel,
+                "text": text,
+                "blocks": blocks
+            })),
+    )?;
     res.error_for_status_ref()?;
     Ok(())
 }
@@ -0,0 +1,349 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug)]
+pub enum StoreError {
+    DatabaseError(rusqlite::Error),
+    SerializationError(serde_json::Error),
+}
+
+impl std::convert::From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::DatabaseError(e)
+    }
+}
+
+impl std::convert::From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::SerializationError(e)
+    }
+}
+
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// What a Slack user was notified about. Notifications are keyed per topic so
+/// build ids can't collide with release versions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Topic {
+    /// A release, keyed by its version like `v42`.
+    Release,
+    /// A failed build, keyed by its build id.
+    Build,
+    /// A dyno crash soon after a release, keyed by the release version.
+    DynoCrash,
+}
+
+impl Topic {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Release => "release",
+            Self::Build => "build",
+            Self::DynoCrash => "dyno_crash",
+        }
+    }
+}
+
+/// How long a claim on creating a pull request comment is held, in case the
+/// job holding it dies before recording the comment.
+const COMMENT_CLAIM_SECONDS: i64 = 60;
+
+/// The latest release of a pull request to an app.
+#[derive(Debug, PartialEq)]
+pub struct PullRequestDeploy {
+    pub app: String,
+    pub release: String,
+    /// The release removed the pull request from the app.
+    pub rolled_back: bool,
+    pub deployed_at: i64,
+}
+
+/// SQLite database shared by the job queue and delivery tracking.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                run_at INTEGER NOT NULL,
+                locked_until INTEGER,
+                last_error TEXT,
+                dead INTEGER NOT NULL DEFAULT 0,
+                ordering_key TEXT,
+                created_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS jobs_run_at ON jobs (dead, run_at);
+            CREATE INDEX IF NOT EXISTS jobs_ordering_key ON jobs (ordering_key, id);
+            CREATE TABLE IF NOT EXISTS webhook_events (
+                id TEXT PRIMARY KEY,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS releases (
+                app TEXT NOT NULL,
+                release TEXT NOT NULL,
+                pending INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (app, release)
+            );
+            CREATE TABLE IF NOT EXISTS builds (
+                app TEXT NOT NULL,
+                build_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (app, build_id)
+            );
+            CREATE TABLE IF NOT EXISTS notifications (
+                topic TEXT NOT NULL,
+                app TEXT NOT NULL,
+                key TEXT NOT NULL,
+                slack_user_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (topic, app, key, slack_user_id)
+            );
+            CREATE TABLE IF NOT EXISTS pull_request_deploys (
+                org TEXT NOT NULL,
+                repo TEXT NOT NULL,
+                number INTEGER NOT NULL,
+                app TEXT NOT NULL,
+                release TEXT NOT NULL,
+                rolled_back INTEGER NOT NULL,
+                deployed_at INTEGER NOT NULL,
+                PRIMARY KEY (org, repo, number, app)
+            );
+            CREATE TABLE IF NOT EXISTS pull_request_comments (
+                org TEXT NOT NULL,
+                repo TEXT NOT NULL,
+                number INTEGER NOT NULL,
+                comment_id INTEGER NOT NULL,
+                PRIMARY KEY (org, repo, number)
+            );
+            CREATE TABLE IF NOT EXISTS pull_request_comment_claims (
+                org TEXT NOT NULL,
+                repo TEXT NOT NULL,
+                number INTEGER NOT NULL,
+                claimed_at INTEGER NOT NULL,
+                PRIMARY KEY (org, repo, number)
+            );
+            ",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn conn(&self) -> MutexGuard<Connection> {
+        self.conn.lock().expect("database connection lock poisoned")
+    }
+
+    /// Check whether `slack_user_id` was already notified about `key`.
+    pub fn is_notified(
+        &self,
+        topic: Topic,
+        app: &str,
+        key: &str,
+        slack_user_id: &str,
+    ) -> Result<bool, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM notifications
+                 WHERE topic = ?1 AND app = ?2 AND key = ?3 AND slack_user_id = ?4",
+                params![topic.as_str(), app, key, slack_user_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// Record that `slack_user_id` was notified about `key` so retries of a
+    /// partially failed job don't message them again.
+    pub fn record_notification(
+        &self,
+        topic: Topic,
+        app: &str,
+        key: &str,
+        slack_user_id: &str,
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO notifications (topic, app, key, slack_user_id, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![topic.as_str(), app, key, slack_user_id, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Record the latest release of a pull request to `deploy.app`,
+    /// replacing earlier releases to the app.
+    pub fn record_pull_request_deploy(
+        &self,
+        org: &str,
+        repo: &str,
+        number: i64,
+        deploy: &PullRequestDeploy,
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO pull_request_deploys
+             (org, repo, number, app, release, rolled_back, deployed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                org,
+                repo,
+                number,
+                deploy.app,
+                deploy.release,
+                deploy.rolled_back,
+                deploy.deployed_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest release of a pull request to each app, oldest first.
+    pub fn pull_request_deploys(
+        &self,
+        org: &str,
+        repo: &str,
+        number: i64,
+    ) -> Result<Vec<PullRequestDeploy>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT app, release, rolled_back, deployed_at FROM pull_request_deploys
+             WHERE org = ?1 AND repo = ?2 AND number = ?3
+             ORDER BY deployed_at, app",
+        )?;
+        let deploys = stmt
+            .query_map(params![org, repo, number], |row| {
+                Ok(PullRequestDeploy {
+                    app: row.get(0)?,
+                    release: row.get(1)?,
+                    rolled_back: row.get(2)?,
+                    deployed_at: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(deploys)
+    }
+
+    /// The GitHub comment Eve keeps on a pull request, if one was created.
+    pub fn pull_request_comment_id(
+        &self,
+        org: &str,
+        repo: &str,
+        number: i64,
+    ) -> Result<Option<i64>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT comment_id FROM pull_request_comments
+                 WHERE org = ?1 AND repo = ?2 AND number = ?3",
+                params![org, repo, number],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Claim creating the comment on a pull request, so jobs deploying it to
+    /// different apps at the same time don't each create one.
+    ///
+    /// Returns `false` if another job holds the claim.
+    pub fn claim_pull_request_comment(
+        &self,
+        org: &str,
+        repo: &str,
+        number: i64,
+    ) -> Result<bool, StoreError> {
+        let now = now();
+        Ok(self.conn().execute(
+            "INSERT INTO pull_request_comment_claims (org, repo, number, claimed_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (org, repo, number) DO UPDATE SET claimed_at = excluded.claimed_at
+             WHERE claimed_at <= ?5",
+            params![org, repo, number, now, now - COMMENT_CLAIM_SECONDS],
+        )? == 1)
+    }
+
+    /// Record the comment created on a pull request, releasing the claim on
+    /// creating it.
+    pub fn record_pull_request_comment(
+        &self,
+        org: &str,
+        repo: &str,
+        number: i64,
+        comment_id: i64,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO pull_request_comments (org, repo, number, comment_id)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![org, repo, number, comment_id],
+        )?;
+        tx.execute(
+            "DELETE FROM pull_request_comment_claims WHERE org = ?1 AND repo = ?2 AND number = ?3",
+            params![org, repo, number],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_claim_pull_request_comment() {
+        let store = Store::open(":memory:").unwrap();
+        assert!(store
+            .claim_pull_request_comment("acme-corp", "blog", 12)
+            .unwrap());
+        assert!(
+            !store
+                .claim_pull_request_comment("acme-corp", "blog", 12)
+                .unwrap(),
+            "comment is being created by another job"
+        );
+        assert!(store
+            .claim_pull_request_comment("acme-corp", "blog", 15)
+            .unwrap());
+
+        store
+            .record_pull_request_comment("acme-corp", "blog", 12, 633)
+            .unwrap();
+        assert_eq!(
+            store
+                .pull_request_comment_id("acme-corp", "blog", 12)
+                .unwrap(),
+            Some(633)
+        );
+        assert!(
+            store
+                .claim_pull_request_comment("acme-corp", "blog", 12)
+                .unwrap(),
+            "recording the comment releases the claim"
+        );
+
+        store
+            .conn()
+            .execute(
+                "UPDATE pull_request_comment_claims SET claimed_at = claimed_at - ?1",
+                params![COMMENT_CLAIM_SECONDS],
+            )
+            .unwrap();
+        assert!(
+            store
+                .claim_pull_request_comment("acme-corp", "blog", 15)
+                .unwrap(),
+            "expired claims can be taken over"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Run the `heroku` subcommands against a local stand-in for the Heroku API.
+use mockito::{mock, Matcher};
+use serde_json::json;
+use structopt::StructOpt;
+
+use eve::cli::Command;
+
+fn command(args: &[&str]) -> Command {
+    let server_url = mockito::server_url();
+    let mut argv = vec![
+        "eve",
+        "heroku",
+        "--url",
+        "https://eve.example.com",
+        "--heroku-token",
+        "heroku-token",
+        "--heroku-api-url",
+        server_url.as_str(),
+    ];
+    argv.extend_from_slice(args);
+    Command::from_iter(argv)
+}
+
+fn webhook(id: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "url": "https://eve.example.com/heroku_webhook",
+        "include": ["api:release", "api:build", "api:dyno"],
+        "level": "notify"
+    })
+}
+
+#[test]
+fn test_subscribe_keeps_existing_secret() {
+    let _list = mock("GET", "/apps/acme-prod/webhooks")
+        .match_header("authorization", "Bearer heroku-token")
+        .with_body(json!([webhook("webhook-1")]).to_string())
+        .create();
+    let update = mock("PATCH", "/apps/acme-prod/webhooks/webhook-1")
+        .match_body(Matcher::Json(json!({
+            "include": ["api:release", "api:build", "api:dyno"],
+            "level": "notify",
+            "url": "https://eve.example.com/heroku_webhook"
+        })))
+        .with_body(webhook("webhook-1").to_string())
+        .expect(1)
+        .create();
+
+    eve::commands::run(&command(&["subscribe", "--app", "acme-prod"])).unwrap();
+    update.assert();
+}
+
+#[test]
+fn test_subscribe_generates_secret_for_new_webhook() {
+    let _list = mock("GET", "/apps/acme-api/webhooks")
+        .with_body("[]")
+        .create();
+    let create = mock("POST", "/apps/acme-api/webhooks")
+        .match_body(Matcher::Regex(r#""secret":"[0-9a-f]{64}""#.to_string()))
+        .with_body(webhook("webhook-2").to_string())
+        .expect(1)
+        .create();
+
+    eve::commands::run(&command(&["subscribe", "--app", "acme-api"])).unwrap();
+    create.assert();
+}